@@ -0,0 +1,377 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use fragments::Fragments;
+use parse::{self, JsonFragment, ParseOptions};
+use templates;
+use value::ParseError;
+
+/// Compile a directory of json templates into a Rust source file.
+///
+/// This is meant to be called from a `build.rs` script.
+/// Each `.json` file in the directory or its subdirectories becomes a function
+/// that renders the template into a `json_str::JsonString`.
+/// Templates are sanitised with the same options as the macros, and the rendered json is
+/// validated when the function is called, panicking if the values made it invalid.
+/// The function is named by the template's path relative to the directory, so
+/// `queries/search.json` becomes `queries_search`.
+/// It takes a parameter for each placeholder in the order they first appear,
+/// which can be any `json_str::templates::TemplateValue`.
+///
+/// Constants, like `{PAGE_SIZE}`, are written as references to items in scope where the
+/// source is included.
+/// Templates can call each other as partials, like `@queries/match(field, value)`.
+/// Arguments are matched to the partial's placeholders by name, like they are by `templates::TemplateSet`.
+/// Calls to partials that don't exist or that don't pass exactly the partial's placeholders are errors,
+/// and so are templates that call themselves through their partials.
+/// So are templates that aren't valid json, treating placeholders and partials as values,
+/// and templates whose paths would give them the same function name, like `a/b_c.json` and `a_b/c.json`.
+///
+/// Cargo is told to rerun the build script whenever a template changes.
+///
+/// # Examples
+///
+/// In `build.rs`:
+///
+/// ```no_run
+/// extern crate json_str;
+///
+/// use std::env;
+/// use std::path::Path;
+///
+/// fn main() {
+///     let out = Path::new(&env::var("OUT_DIR").unwrap()).join("templates.rs");
+///
+///     json_str::build::compile_templates("templates", out).unwrap();
+/// }
+/// ```
+///
+/// Then in the crate that needs the templates:
+///
+/// ```ignore
+/// include!(concat!(env!("OUT_DIR"), "/templates.rs"));
+///
+/// let json = queries_search("title", "some value", 10);
+/// ```
+pub fn compile_templates<P, Q>(dir: P, out: Q) -> Result<(), BuildError>
+    where P: AsRef<Path>,
+          Q: AsRef<Path>
+{
+    let dir = dir.as_ref();
+
+    let mut paths = Vec::new();
+    templates::template_paths(dir, &mut paths)?;
+
+    println!("cargo:rerun-if-changed={}", dir.display());
+    for path in &paths {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+
+    let src = generate_templates(dir)?;
+    fs::write(out, src)?;
+
+    Ok(())
+}
+
+/// Generate Rust source for a directory of json templates.
+///
+/// This is the same as `compile_templates`, but returns the source instead of writing it to a file.
+pub fn generate_templates<P>(dir: P) -> Result<String, BuildError>
+    where P: AsRef<Path>
+{
+    let dir = dir.as_ref();
+
+    let mut paths = Vec::new();
+    templates::template_paths(dir, &mut paths)?;
+
+    let opts = ParseOptions::strict();
+
+    let mut templates = Vec::new();
+    for path in paths {
+        let name = templates::template_name(dir, &path);
+        let json = fs::read_to_string(&path)?;
+
+        templates.push((name, json));
+    }
+
+    // Check each template gets its own function
+    for (i, (name, _)) in templates.iter().enumerate() {
+        if let Some((other, _)) = templates[..i].iter().find(|(other, _)| fn_ident(other) == fn_ident(name)) {
+            return Err(BuildError::DuplicateName {
+                template: name.to_owned(),
+                other: other.to_owned(),
+                ident: fn_ident(name)
+            });
+        }
+    }
+
+    // Check no template calls itself, directly or through other partials
+    let calls: Vec<(&str, Vec<&str>)> = templates
+        .iter()
+        .map(|(name, json)| (name.as_ref(), partial_calls(json, &opts)))
+        .collect();
+
+    if let Some(template) = recursive_partial(&calls) {
+        return Err(BuildError::RecursivePartial {
+            template: template.to_owned()
+        });
+    }
+
+    // Get the placeholders for each template so partial calls can be checked
+    let params: Vec<(&str, Vec<String>)> = templates
+        .iter()
+        .map(|(name, json)| {
+            let placeholders = Fragments::parse_with(json, &opts).placeholders().map(|p| p.to_owned()).collect();
+
+            (name.as_ref(), placeholders)
+        })
+        .collect();
+
+    let mut src = String::new();
+
+    for ((name, json), (_, placeholders)) in templates.iter().zip(params.iter()) {
+        if let Err(err) = templates::check_json(&Fragments::parse_with(json, &opts)) {
+            return Err(BuildError::InvalidJson {
+                template: name.to_owned(),
+                err
+            });
+        }
+
+        let mut fragments = Vec::new();
+        parse::parse_fragments_with(json.as_bytes(), &mut fragments, &opts);
+
+        let args: Vec<String> = placeholders
+            .iter()
+            .map(|p| format!("{}: impl ::json_str::templates::TemplateValue", ident(p)))
+            .collect();
+
+        let _ = writeln!(src, "/// Render the `{}` template.", name);
+        let _ = writeln!(src, "#[allow(dead_code)]");
+        let _ = writeln!(src, "pub fn {}({}) -> ::json_str::JsonString {{", fn_ident(name), args.join(", "));
+        let _ = writeln!(src, "    let mut result = String::new();");
+
+        for fragment in fragments {
+            match fragment {
+                JsonFragment::Literal(l) => {
+                    let _ = writeln!(src, "    result.push_str({:?});", l);
+                },
+                JsonFragment::Repl(r) => {
+                    let _ = writeln!(src, "    ::json_str::templates::TemplateValue::write_json(&{}, &mut result);", ident(r));
+                },
+                JsonFragment::KeyRepl(r) => {
+                    let _ = writeln!(src, "    result.push('\"');");
+                    let _ = writeln!(src, "    ::json_str::templates::TemplateValue::write_escaped(&{}, &mut result);", ident(r));
+                    let _ = writeln!(src, "    result.push('\"');");
+                },
+                JsonFragment::StrRepl(r) => {
+                    let _ = writeln!(src, "    ::json_str::templates::TemplateValue::write_escaped(&{}, &mut result);", ident(r));
+                },
+                JsonFragment::Const(c) => {
+                    let _ = writeln!(src, "    ::json_str::templates::TemplateValue::write_json(&{}, &mut result);", c);
+                },
+                JsonFragment::Partial(partial, args) => {
+                    let expected = params
+                        .iter()
+                        .find(|&&(n, _)| n == partial)
+                        .map(|(_, p)| p)
+                        .ok_or_else(|| BuildError::UnknownPartial {
+                            template: name.to_owned(),
+                            partial: partial.to_owned()
+                        })?;
+
+                    // Args are matched to the partial's placeholders by name
+                    if expected.len() != args.len() || !expected.iter().all(|p| args.contains(&&**p)) {
+                        return Err(BuildError::PartialArgs {
+                            template: name.to_owned(),
+                            partial: partial.to_owned(),
+                            expected: expected.clone(),
+                            actual: args.iter().map(|a| (*a).to_owned()).collect()
+                        });
+                    }
+
+                    let args: Vec<String> = expected.iter().map(|a| format!("&{}", ident(a))).collect();
+
+                    let _ = writeln!(src, "    result.push_str(&{}({}));", fn_ident(partial), args.join(", "));
+                }
+            }
+        }
+
+        let _ = writeln!(src, "    ::json_str::JsonString::from_string(result).unwrap_or_else(|e| panic!(\"template '{}' produced invalid json: {{}}\", e))", name);
+        let _ = writeln!(src, "}}");
+        let _ = writeln!(src);
+    }
+
+    Ok(src)
+}
+
+// Get the names of the partials a template calls.
+fn partial_calls<'a>(json: &'a str, opts: &ParseOptions) -> Vec<&'a str> {
+    let mut fragments = Vec::new();
+    parse::parse_fragments_with(json.as_bytes(), &mut fragments, opts);
+
+    fragments
+        .into_iter()
+        .filter_map(|f| match f {
+            JsonFragment::Partial(partial, _) => Some(partial),
+            _ => None
+        })
+        .collect()
+}
+
+// Find a template that calls itself through its partials.
+fn recursive_partial<'a>(calls: &[(&'a str, Vec<&'a str>)]) -> Option<&'a str> {
+    // Visit the partials a template calls, keeping track of the templates on the way there
+    fn visit<'a>(name: &'a str, calls: &[(&'a str, Vec<&'a str>)], path: &mut Vec<&'a str>, done: &mut Vec<&'a str>) -> Option<&'a str> {
+        if path.contains(&name) {
+            return Some(name);
+        }
+
+        if done.contains(&name) {
+            return None;
+        }
+
+        //Calls to partials that don't exist are reported later
+        let partials = match calls.iter().find(|&&(n, _)| n == name) {
+            Some((_, partials)) => partials,
+            None => return None
+        };
+
+        path.push(name);
+
+        for partial in partials {
+            if let Some(template) = visit(partial, calls, path, done) {
+                return Some(template);
+            }
+        }
+
+        path.pop();
+        done.push(name);
+
+        None
+    }
+
+    let mut done = Vec::new();
+
+    for &(name, _) in calls {
+        if let Some(template) = visit(name, calls, &mut Vec::new(), &mut done) {
+            return Some(template);
+        }
+    }
+
+    None
+}
+
+// Get a function name for a template name.
+fn fn_ident(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+
+    match name.chars().next() {
+        Some(c) if c.is_numeric() => format!("_{}", name),
+        _ => ident(&name)
+    }
+}
+
+// Get an identifier that won't clash with a keyword or the `result` local.
+fn ident(name: &str) -> String {
+    match name {
+        "self" | "Self" | "super" | "crate" | "result" => format!("{}_", name),
+        "as" | "break" | "const" | "continue" | "else" | "enum" | "extern" | "false" | "fn" | "for" |
+        "if" | "impl" | "in" | "let" | "loop" | "match" | "mod" | "move" | "mut" | "pub" | "ref" |
+        "return" | "static" | "struct" | "trait" | "true" | "type" | "unsafe" | "use" | "where" |
+        "while" | "abstract" | "become" | "box" | "do" | "final" | "macro" | "override" | "priv" |
+        "typeof" | "unsized" | "virtual" | "yield" | "async" | "await" | "dyn" | "try" | "gen" => format!("r#{}", name),
+        _ => name.to_owned()
+    }
+}
+
+/// An error compiling a directory of templates.
+#[derive(Debug)]
+pub enum BuildError {
+    /// The templates couldn't be read or the source couldn't be written.
+    Io(io::Error),
+    /// A template isn't valid json.
+    InvalidJson {
+        /// The invalid template.
+        template: String,
+        /// The error validating the template.
+        err: ParseError
+    },
+    /// A template calls a partial that doesn't exist.
+    UnknownPartial {
+        /// The template calling the partial.
+        template: String,
+        /// The partial that doesn't exist.
+        partial: String
+    },
+    /// A template calls a partial with arguments that aren't the partial's placeholders.
+    PartialArgs {
+        /// The template calling the partial.
+        template: String,
+        /// The partial being called.
+        partial: String,
+        /// The placeholders in the partial.
+        expected: Vec<String>,
+        /// The arguments the partial was called with.
+        actual: Vec<String>
+    },
+    /// A template calls itself, directly or through other partials.
+    RecursivePartial {
+        /// The template that calls itself.
+        template: String
+    },
+    /// Two templates would compile to functions with the same name.
+    DuplicateName {
+        /// The second template with the name.
+        template: String,
+        /// The first template with the name.
+        other: String,
+        /// The function name they share.
+        ident: String
+    }
+}
+
+impl From<io::Error> for BuildError {
+    fn from(err: io::Error) -> Self {
+        BuildError::Io(err)
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::Io(ref err) => err.fmt(f),
+            BuildError::InvalidJson { ref template, ref err } => {
+                write!(f, "template '{}' is invalid json: {}", template, err)
+            },
+            BuildError::UnknownPartial { ref template, ref partial } => {
+                write!(f, "template '{}' calls partial '{}', which doesn't exist", template, partial)
+            },
+            BuildError::PartialArgs { ref template, ref partial, ref expected, ref actual } => {
+                write!(f, "template '{}' calls partial '{}' with args ({}), but it expects ({})", template, partial, actual.join(", "), expected.join(", "))
+            },
+            BuildError::RecursivePartial { ref template } => {
+                write!(f, "template '{}' calls itself as a partial", template)
+            },
+            BuildError::DuplicateName { ref template, ref other, ref ident } => {
+                write!(f, "templates '{}' and '{}' would both compile to the function '{}'", other, template, ident)
+            }
+        }
+    }
+}
+
+impl Error for BuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            BuildError::Io(ref err) => Some(err),
+            BuildError::InvalidJson { ref err, .. } => Some(err),
+            _ => None
+        }
+    }
+}
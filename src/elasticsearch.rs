@@ -0,0 +1,457 @@
+use std::error::Error;
+use std::fmt;
+
+use value::Value;
+use JsonStr;
+
+/// Check that json is a valid Elasticsearch search body.
+///
+/// The `query`, `post_filter` and any aggregations are checked against a description of the query DSL,
+/// so misspelt queries, `bool` sections and aggregation types are caught, along with
+/// aggregations that are missing a required parameter.
+/// The fields and values queries are given aren't checked.
+///
+/// The description follows the Elasticsearch 8 query DSL, including the queries and aggregations
+/// of its default distribution's plugins, like `rate` and `sparse_vector`.
+/// Bodies of `retriever`, `knn` and `rank` sections aren't checked.
+///
+/// # Examples
+///
+/// ```
+/// use json_str::JsonStr;
+/// use json_str::elasticsearch;
+///
+/// let body = JsonStr::from_str("{\"query\":{\"match_al\":{}}}").unwrap();
+/// let err = elasticsearch::validate(body).unwrap_err();
+///
+/// assert_eq!("unknown query 'match_al' at '/query', did you mean 'match_all'?", err.to_string());
+/// ```
+pub fn validate(json: &JsonStr) -> Result<(), QueryError> {
+    let value = Value::parse(json).expect("a JsonStr contains invalid json");
+
+    let mut path = String::new();
+    search_body(&value, &mut path)
+}
+
+const BODY_KEYS: &[&str] = &[
+    "query", "post_filter", "aggs", "aggregations", "size", "from", "sort", "_source", "fields",
+    "stored_fields", "docvalue_fields", "script_fields", "highlight", "suggest", "collapse", "rescore",
+    "search_after", "track_total_hits", "track_scores", "timeout", "terminate_after", "min_score",
+    "explain", "version", "seq_no_primary_term", "indices_boost", "runtime_mappings", "pit", "knn",
+    "profile", "stats", "ext", "slice", "retriever", "rank", "sub_searches"
+];
+
+// Leaf queries, whose bodies aren't checked.
+const LEAF_QUERIES: &[&str] = &[
+    "match_all", "match_none", "match", "match_phrase", "match_phrase_prefix", "match_bool_prefix",
+    "multi_match", "combined_fields", "query_string", "simple_query_string", "intervals",
+    "term", "terms", "terms_set", "range", "exists", "prefix", "wildcard", "regexp", "fuzzy", "ids", "type",
+    "geo_distance", "geo_bounding_box", "geo_polygon", "geo_shape", "shape", "parent_id", "more_like_this",
+    "geo_grid", "script", "percolate", "rank_feature", "distance_feature", "wrapper", "knn",
+    "sparse_vector", "text_expansion", "weighted_tokens", "semantic",
+    "span_term", "span_multi", "span_first", "span_near", "span_or", "span_not", "span_containing",
+    "span_within", "field_masking_span", "span_field_masking"
+];
+
+// A compound query, with the parameters that hold other queries and the others it accepts.
+// Parameters of compound queries with `None` aren't checked.
+type Compound = (&'static str, &'static [&'static str], Option<&'static [&'static str]>);
+
+const COMPOUND_QUERIES: &[Compound] = &[
+    ("bool", &["must", "filter", "should", "must_not"], Some(&["minimum_should_match", "boost", "_name", "disable_coord", "adjust_pure_negative"])),
+    ("dis_max", &["queries"], Some(&["tie_breaker", "boost", "_name"])),
+    ("constant_score", &["filter"], Some(&["boost", "_name"])),
+    ("boosting", &["positive", "negative"], Some(&["negative_boost", "boost", "_name"])),
+    ("filtered", &["query", "filter"], Some(&["boost", "_name"])),
+    ("function_score", &["query"], None),
+    ("script_score", &["query"], None),
+    ("nested", &["query"], None),
+    ("has_child", &["query"], None),
+    ("has_parent", &["query"], None),
+    ("pinned", &["organic"], None),
+    ("rule", &["organic"], None)
+];
+
+// Aggregation types, with the parameters they require.
+// Each inner list is a set of alternatives, where one of them must be given.
+const AGGREGATIONS: &[(&str, &[&[&str]])] = &[
+    ("terms", &[&["field", "script"]]),
+    ("rare_terms", &[&["field"]]),
+    ("multi_terms", &[&["terms"]]),
+    ("significant_terms", &[&["field"]]),
+    ("significant_text", &[&["field"]]),
+    ("histogram", &[&["field", "script"], &["interval"]]),
+    ("date_histogram", &[&["field", "script"], &["interval", "calendar_interval", "fixed_interval"]]),
+    ("auto_date_histogram", &[&["field", "script"]]),
+    ("variable_width_histogram", &[&["field"]]),
+    ("range", &[&["field", "script"], &["ranges"]]),
+    ("date_range", &[&["field", "script"], &["ranges"]]),
+    ("ip_range", &[&["field"], &["ranges"]]),
+    ("geo_distance", &[&["field"], &["origin"], &["ranges"]]),
+    ("geohash_grid", &[&["field"]]),
+    ("geotile_grid", &[&["field"]]),
+    ("geohex_grid", &[&["field"]]),
+    ("ip_prefix", &[&["field"], &["prefix_length"]]),
+    ("categorize_text", &[&["field"]]),
+    ("frequent_item_sets", &[&["fields"]]),
+    ("time_series", &[]),
+    ("missing", &[&["field"]]),
+    ("composite", &[&["sources"]]),
+    ("filter", &[]),
+    ("filters", &[&["filters"]]),
+    ("adjacency_matrix", &[&["filters"]]),
+    ("nested", &[&["path"]]),
+    ("reverse_nested", &[]),
+    ("children", &[&["type"]]),
+    ("parent", &[&["type"]]),
+    ("global", &[]),
+    ("sampler", &[]),
+    ("diversified_sampler", &[&["field", "script"]]),
+    ("random_sampler", &[&["probability"]]),
+    ("avg", &[&["field", "script"]]),
+    ("sum", &[&["field", "script"]]),
+    ("min", &[&["field", "script"]]),
+    ("max", &[&["field", "script"]]),
+    ("stats", &[&["field", "script"]]),
+    ("extended_stats", &[&["field", "script"]]),
+    ("value_count", &[&["field", "script"]]),
+    ("cardinality", &[&["field", "script"]]),
+    ("percentiles", &[&["field", "script"]]),
+    ("percentile_ranks", &[&["field", "script"], &["values"]]),
+    ("median_absolute_deviation", &[&["field", "script"]]),
+    ("weighted_avg", &[&["value"], &["weight"]]),
+    ("rate", &[]),
+    ("boxplot", &[&["field", "script"]]),
+    ("string_stats", &[&["field", "script"]]),
+    ("matrix_stats", &[&["fields"]]),
+    ("t_test", &[&["a"], &["b"]]),
+    ("geo_bounds", &[&["field"]]),
+    ("geo_centroid", &[&["field"]]),
+    ("geo_line", &[&["point"], &["sort"]]),
+    ("cartesian_bounds", &[&["field"]]),
+    ("cartesian_centroid", &[&["field"]]),
+    ("top_hits", &[]),
+    ("top_metrics", &[&["metrics"], &["sort"]]),
+    ("scripted_metric", &[&["map_script"]]),
+    ("avg_bucket", &[&["buckets_path"]]),
+    ("sum_bucket", &[&["buckets_path"]]),
+    ("min_bucket", &[&["buckets_path"]]),
+    ("max_bucket", &[&["buckets_path"]]),
+    ("stats_bucket", &[&["buckets_path"]]),
+    ("extended_stats_bucket", &[&["buckets_path"]]),
+    ("percentiles_bucket", &[&["buckets_path"]]),
+    ("derivative", &[&["buckets_path"]]),
+    ("cumulative_sum", &[&["buckets_path"]]),
+    ("cumulative_cardinality", &[&["buckets_path"]]),
+    ("serial_diff", &[&["buckets_path"]]),
+    ("moving_avg", &[&["buckets_path"]]),
+    ("moving_fn", &[&["buckets_path"], &["script"]]),
+    ("moving_percentiles", &[&["buckets_path"], &["window"]]),
+    ("normalize", &[&["buckets_path"], &["method"]]),
+    ("bucket_count_ks_test", &[&["buckets_path"]]),
+    ("bucket_correlation", &[&["buckets_path"], &["function"]]),
+    ("change_point", &[&["buckets_path"]]),
+    ("inference", &[&["model_id"]]),
+    ("bucket_script", &[&["buckets_path"], &["script"]]),
+    ("bucket_selector", &[&["buckets_path"], &["script"]]),
+    ("bucket_sort", &[])
+];
+
+fn search_body(value: &Value, path: &mut String) -> Result<(), QueryError> {
+    let members = match *value {
+        Value::Object(ref members) => members,
+        _ => return Err(QueryError::new(path, "expected a search body object".to_owned()))
+    };
+
+    for (key, value) in members {
+        let len = path.len();
+        push_segment(path, key);
+
+        match &**key {
+            "query" | "post_filter" => query(value, path)?,
+            "aggs" | "aggregations" => aggregations(value, path)?,
+            key if BODY_KEYS.contains(&key) => (),
+            key => {
+                path.truncate(len);
+                return Err(QueryError::unknown("search body key", key, BODY_KEYS, path));
+            }
+        }
+
+        path.truncate(len);
+    }
+
+    Ok(())
+}
+
+fn query(value: &Value, path: &mut String) -> Result<(), QueryError> {
+    let (name, body) = match *value {
+        Value::Object(ref members) if members.len() == 1 => (&members[0].0, &members[0].1),
+        _ => return Err(QueryError::new(path, "expected an object with a single query".to_owned()))
+    };
+
+    if LEAF_QUERIES.contains(&&**name) {
+        return Ok(());
+    }
+
+    let (queries, params) = match COMPOUND_QUERIES.iter().find(|&&(compound, _, _)| compound == name) {
+        Some(&(_, queries, params)) => (queries, params),
+        None => {
+            let names: Vec<&'static str> = LEAF_QUERIES.iter().cloned().chain(COMPOUND_QUERIES.iter().map(|&(name, _, _)| name)).collect();
+
+            return Err(QueryError::unknown("query", name, &names, path));
+        }
+    };
+
+    let len = path.len();
+    push_segment(path, name);
+
+    let members = match *body {
+        Value::Object(ref members) => members,
+        _ => return Err(QueryError::new(path, format!("expected an object for '{}'", name)))
+    };
+
+    for (key, value) in members {
+        if queries.contains(&&**key) {
+            let len = path.len();
+            push_segment(path, key);
+
+            //Sections can be a single query or an array of them
+            match *value {
+                Value::Array(ref values) => {
+                    for (i, value) in values.iter().enumerate() {
+                        let len = path.len();
+                        push_segment(path, &i.to_string());
+
+                        query(value, path)?;
+                        path.truncate(len);
+                    }
+                },
+                ref value => query(value, path)?
+            }
+
+            path.truncate(len);
+        }
+        else if let Some(params) = params {
+            if !params.contains(&&**key) {
+                let expected: Vec<&'static str> = queries.iter().chain(params).cloned().collect();
+
+                return Err(QueryError::unknown(&format!("{} parameter", name), key, &expected, path));
+            }
+        }
+    }
+
+    path.truncate(len);
+
+    Ok(())
+}
+
+fn aggregations(value: &Value, path: &mut String) -> Result<(), QueryError> {
+    let members = match *value {
+        Value::Object(ref members) => members,
+        _ => return Err(QueryError::new(path, "expected an object of named aggregations".to_owned()))
+    };
+
+    for (name, agg) in members {
+        let len = path.len();
+        push_segment(path, name);
+
+        aggregation(agg, path)?;
+
+        path.truncate(len);
+    }
+
+    Ok(())
+}
+
+fn aggregation(value: &Value, path: &mut String) -> Result<(), QueryError> {
+    let members = match *value {
+        Value::Object(ref members) => members,
+        _ => return Err(QueryError::new(path, "expected an aggregation object".to_owned()))
+    };
+
+    let mut has_type = false;
+
+    for (key, body) in members {
+        let len = path.len();
+        push_segment(path, key);
+
+        match &**key {
+            "aggs" | "aggregations" => aggregations(body, path)?,
+            "meta" => (),
+            key => {
+                let required = match AGGREGATIONS.iter().find(|&&(agg, _)| agg == key) {
+                    Some(&(_, required)) => required,
+                    None => {
+                        let names: Vec<&'static str> = AGGREGATIONS.iter().map(|&(name, _)| name).collect();
+
+                        path.truncate(len);
+                        return Err(QueryError::unknown("aggregation", key, &names, path));
+                    }
+                };
+
+                if has_type {
+                    path.truncate(len);
+                    return Err(QueryError::new(path, "expected a single aggregation type".to_owned()));
+                }
+                has_type = true;
+
+                aggregation_body(key, required, body, path)?;
+            }
+        }
+
+        path.truncate(len);
+    }
+
+    if !has_type {
+        return Err(QueryError::new(path, "expected an aggregation type".to_owned()));
+    }
+
+    Ok(())
+}
+
+fn aggregation_body(name: &str, required: &[&[&str]], body: &Value, path: &mut String) -> Result<(), QueryError> {
+    //The body of a `filter` aggregation is a query
+    if name == "filter" {
+        return query(body, path);
+    }
+
+    let params = match *body {
+        Value::Object(ref params) => params,
+        _ => return Err(QueryError::new(path, format!("expected an object for '{}'", name)))
+    };
+
+    for alternatives in required {
+        if !params.iter().any(|(param, _)| alternatives.contains(&&**param)) {
+            let expected = alternatives.iter().map(|a| format!("'{}'", a)).collect::<Vec<_>>().join(" or ");
+
+            return Err(QueryError::new(path, format!("'{}' aggregation is missing {}", name, expected)));
+        }
+    }
+
+    //Named or anonymous filters are queries too
+    if name == "filters" || name == "adjacency_matrix" {
+        for (param, filters) in params {
+            if param != "filters" {
+                continue;
+            }
+
+            let len = path.len();
+            push_segment(path, param);
+
+            match *filters {
+                Value::Object(ref filters) => {
+                    for (key, filter) in filters {
+                        let len = path.len();
+                        push_segment(path, key);
+
+                        query(filter, path)?;
+                        path.truncate(len);
+                    }
+                },
+                Value::Array(ref filters) => {
+                    for (i, filter) in filters.iter().enumerate() {
+                        let len = path.len();
+                        push_segment(path, &i.to_string());
+
+                        query(filter, path)?;
+                        path.truncate(len);
+                    }
+                },
+                _ => return Err(QueryError::new(path, "expected an object or array of filters".to_owned()))
+            }
+
+            path.truncate(len);
+        }
+    }
+
+    Ok(())
+}
+
+// Add a segment to a JSON Pointer.
+fn push_segment(path: &mut String, segment: &str) {
+    path.push('/');
+    path.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+}
+
+// Find the closest expected name to a misspelt one, if there's one that's close enough.
+fn suggest<'a>(name: &str, expected: &[&'a str]) -> Option<&'a str> {
+    expected
+        .iter()
+        .map(|&e| (distance(name, e), e))
+        .filter(|&(d, _)| d <= 2)
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, e)| e)
+}
+
+// The Levenshtein distance between two names.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+
+        for (j, &cb) in b.iter().enumerate() {
+            let next = (row[j + 1] + 1).min(row[j] + 1).min(prev + usize::from(ca != cb));
+
+            prev = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Json isn't a valid Elasticsearch search body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryError {
+    path: String,
+    message: String,
+    suggestion: Option<&'static str>
+}
+
+impl QueryError {
+    fn new(path: &str, message: String) -> Self {
+        QueryError {
+            path: path.to_owned(),
+            message,
+            suggestion: None
+        }
+    }
+
+    fn unknown(kind: &str, name: &str, expected: &[&'static str], path: &str) -> Self {
+        QueryError {
+            path: path.to_owned(),
+            message: format!("unknown {} '{}'", kind, name),
+            suggestion: suggest(name, expected)
+        }
+    }
+
+    /// A JSON Pointer to the part of the body with the problem.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// A known name that's close to an unknown one.
+    pub fn suggestion(&self) -> Option<&str> {
+        self.suggestion
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)?;
+
+        if !self.path.is_empty() {
+            write!(f, " at '{}'", self.path)?;
+        }
+
+        match self.suggestion {
+            Some(suggestion) => write!(f, ", did you mean '{}'?", suggestion),
+            None => Ok(())
+        }
+    }
+}
+
+impl Error for QueryError {}
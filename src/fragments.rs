@@ -0,0 +1,273 @@
+use std::borrow::{Borrow, Cow};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+
+use parse::{self, JsonFragment, ParseOptions};
+
+/// A sequence of parsed json fragments that can be rendered with runtime replacement values.
+///
+/// This is the runtime equivalent of `json_fn`, for templates that aren't known at compile-time.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use json_str::fragments::Fragments;
+///
+/// let fragments = Fragments::parse("{ query: { query_string: { query: $qry } } }");
+///
+/// let mut values = HashMap::new();
+/// values.insert("qry", "\"*\"");
+///
+/// let json = fragments.render_with(&values).unwrap();
+///
+/// assert_eq!("{\"query\":{\"query_string\":{\"query\":\"*\"}}}", json);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fragments {
+    fragments: Vec<Fragment>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Fragment {
+    Literal(String),
+    Repl(String),
+    KeyRepl(String),
+    StrRepl(String),
+    Partial(String, Vec<String>),
+    Const(String)
+}
+
+impl Fragments {
+    /// Parse and sanitise a json template.
+    pub fn parse(json: &str) -> Self {
+        Fragments::parse_with(json, &ParseOptions::default())
+    }
+
+    /// Parse and sanitise a json template with the given options.
+    pub fn parse_with(json: &str, opts: &ParseOptions) -> Self {
+        let mut fragments = Vec::new();
+        parse::parse_fragments_with(json.as_bytes(), &mut fragments, opts);
+
+        Fragments::from(fragments)
+    }
+
+    /// Get the names of the placeholders in the template.
+    ///
+    /// Each name is only returned once, in the order it first appears.
+    /// Placeholders passed as arguments to partials are included, but constants aren't.
+    pub fn placeholders(&self) -> Placeholders<'_> {
+        Placeholders {
+            fragments: &self.fragments,
+            args: &[],
+            seen: Vec::new()
+        }
+    }
+
+    /// Render the template, looking up placeholder values with the given resolver.
+    ///
+    /// Values are spliced in as-is, except for placeholders in key position or interpolated
+    /// into strings, which are escaped.
+    /// Constants, like `{PAGE_SIZE}`, are looked up by name too.
+    /// Templates that call partials can't be rendered this way, so calling a partial is treated
+    /// as a missing placeholder.
+    pub fn render_with<R>(&self, resolver: &R) -> Result<String, MissingPlaceholder>
+        where R: Resolver + ?Sized
+    {
+        let mut json = String::new();
+
+        self.render_into(resolver, &mut |name, _, _| Err(MissingPlaceholder::new(name)), &mut json)?;
+
+        Ok(json)
+    }
+
+    // Render the template, using the given function to render any partials.
+    // The partial function is given the names of the placeholders passed as arguments.
+    pub(crate) fn render_into<R, P, E>(&self, resolver: &R, partial: &mut P, json: &mut String) -> Result<(), E>
+        where R: Resolver + ?Sized,
+              P: FnMut(&str, &[String], &mut String) -> Result<(), E>,
+              E: From<MissingPlaceholder>
+    {
+        let missing = |name: &str| MissingPlaceholder::new(name);
+
+        for f in &self.fragments {
+            match *f {
+                Fragment::Literal(ref l) => json.push_str(l),
+                Fragment::Repl(ref r) | Fragment::Const(ref r) => {
+                    let val = resolver.resolve(r).ok_or_else(|| missing(r))?;
+
+                    json.push_str(&val);
+                },
+                Fragment::KeyRepl(ref r) => {
+                    let val = resolver.resolve_str(r).ok_or_else(|| missing(r))?;
+
+                    json.push('"');
+                    parse::escape(&val, json);
+                    json.push('"');
+                },
+                Fragment::StrRepl(ref r) => {
+                    let val = resolver.resolve_str(r).ok_or_else(|| missing(r))?;
+
+                    parse::escape(&val, json);
+                },
+                Fragment::Partial(ref p, ref args) => partial(p, args, json)?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> From<Vec<JsonFragment<'a>>> for Fragments {
+    fn from(fragments: Vec<JsonFragment<'a>>) -> Self {
+        let fragments = fragments
+            .into_iter()
+            .map(|f| match f {
+                JsonFragment::Literal(l) => Fragment::Literal(l),
+                JsonFragment::Repl(r) => Fragment::Repl(r.to_owned()),
+                JsonFragment::KeyRepl(r) => Fragment::KeyRepl(r.to_owned()),
+                JsonFragment::StrRepl(r) => Fragment::StrRepl(r.to_owned()),
+                JsonFragment::Const(c) => Fragment::Const(c.to_owned()),
+                JsonFragment::Partial(p, args) => {
+                    Fragment::Partial(p.to_owned(), args.into_iter().map(|a| a.to_owned()).collect())
+                }
+            })
+            .collect();
+
+        Fragments {
+            fragments
+        }
+    }
+}
+
+/// An iterator over the names of placeholders in a template.
+pub struct Placeholders<'a> {
+    fragments: &'a [Fragment],
+    args: &'a [String],
+    seen: Vec<&'a str>
+}
+
+impl<'a> Iterator for Placeholders<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        loop {
+            let name = if let Some((arg, rest)) = self.args.split_first() {
+                self.args = rest;
+
+                arg.as_ref()
+            }
+            else {
+                let (f, rest) = self.fragments.split_first()?;
+                self.fragments = rest;
+
+                match *f {
+                    Fragment::Literal(_) | Fragment::Const(_) => continue,
+                    Fragment::Repl(ref r) | Fragment::KeyRepl(ref r) | Fragment::StrRepl(ref r) => r.as_ref(),
+                    Fragment::Partial(_, ref args) => {
+                        self.args = args;
+                        continue;
+                    }
+                }
+            };
+
+            if !self.seen.contains(&name) {
+                self.seen.push(name);
+
+                return Some(name);
+            }
+        }
+    }
+}
+
+/// A source of values for placeholders in a template.
+pub trait Resolver {
+    /// Get the value for the placeholder with the given name.
+    fn resolve(&self, name: &str) -> Option<Cow<'_, str>>;
+
+    /// Get the value for the placeholder with the given name when it's in key position or
+    /// interpolated into a string.
+    ///
+    /// The value will be escaped, so it shouldn't be quoted.
+    /// By default, this is the same as `resolve`.
+    fn resolve_str(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.resolve(name)
+    }
+}
+
+impl<K, V, S> Resolver for HashMap<K, V, S>
+    where K: Borrow<str> + Hash + Eq,
+          V: AsRef<str>,
+          S: BuildHasher
+{
+    fn resolve(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.get(name).map(|v| Cow::Borrowed(v.as_ref()))
+    }
+}
+
+impl<K, V> Resolver for BTreeMap<K, V>
+    where K: Borrow<str> + Ord,
+          V: AsRef<str>
+{
+    fn resolve(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.get(name).map(|v| Cow::Borrowed(v.as_ref()))
+    }
+}
+
+impl<K, V> Resolver for [(K, V)]
+    where K: AsRef<str>,
+          V: AsRef<str>
+{
+    fn resolve(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.iter()
+            .find(|&(k, _)| k.as_ref() == name)
+            .map(|(_, v)| Cow::Borrowed(v.as_ref()))
+    }
+}
+
+impl<K, V, const N: usize> Resolver for [(K, V); N]
+    where K: AsRef<str>,
+          V: AsRef<str>
+{
+    fn resolve(&self, name: &str) -> Option<Cow<'_, str>> {
+        self[..].resolve(name)
+    }
+}
+
+impl<F, T> Resolver for F
+    where F: Fn(&str) -> Option<T>,
+          T: Into<String>
+{
+    fn resolve(&self, name: &str) -> Option<Cow<'_, str>> {
+        self(name).map(|v| Cow::Owned(v.into()))
+    }
+}
+
+/// A placeholder in a template has no value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingPlaceholder {
+    name: String
+}
+
+impl MissingPlaceholder {
+    fn new(name: &str) -> Self {
+        MissingPlaceholder {
+            name: name.to_owned()
+        }
+    }
+
+    /// The name of the placeholder with no value.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl fmt::Display for MissingPlaceholder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "placeholder '{}' has no value", self.name)
+    }
+}
+
+impl Error for MissingPlaceholder {}
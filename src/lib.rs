@@ -51,7 +51,7 @@
 //!
 //! ### Literals
 //! 
-//! The `json_str!` macro will take an inline token tree and return a sanitised json `String`:
+//! The `json_str!` macro will take an inline token tree and return a sanitised `JsonString`:
 //!
 //! ```ignore
 //! let json = json_str!({
@@ -97,9 +97,17 @@
 //! });
 //! ```
 //!
-//! On `nightly`, there's an additional plugin called `json_lit` that returns a `&'static str`
-//! instead of a `String`, so you can avoid allocating each time. The syntax is otherwise the same
-//! as `json_str`:
+//! Unquoted keys can contain digits, `-` and `.`, and start with `@`, like `@timestamp` or `user-agent`.
+//! Raw identifiers like `r#type` are quoted without the `r#`, and numbers in key position are quoted too.
+//!
+//! String values can be written as Rust string literals, including raw and byte strings
+//! like `r#"a "raw" string"#` and escapes like `"\u{1F600}"` or `"\0"`.
+//! They're converted into equivalent json strings.
+//! Control characters in strings, like the newlines in a multi-line string, are escaped.
+//!
+//! There's also a `json_lit` macro that returns a `&'static JsonStr` instead of a `JsonString`,
+//! so you can avoid allocating each time. The json is built the first time it's used,
+//! and cached from then on. The syntax is otherwise the same as `json_str`:
 //!
 //! ```ignore
 //! let json = json_lit!({
@@ -122,13 +130,31 @@
 //! });
 //! ```
 //! 
+//! `json_lit` can also refer to constants with `{NAME}`.
+//! Their values are written as json by `templates::TemplateValue`, so strings are quoted and escaped.
+//! Macros can't see the values of constants, so they aren't baked into the literal.
+//! Instead, a `json_lit` is built at runtime, the first time it's used, and cached in a static from then on.
+//! A body or constant that produces invalid json panics then, rather than failing to compile.
+//! The constants a body refers to are listed before it:
+//!
+//! ```ignore
+//! const INDEX_NAME: &str = "logs";
+//! const PAGE_SIZE: usize = 10;
+//!
+//! // Produces {"index":"logs","size":10}
+//! let json = json_lit!({INDEX_NAME}, {PAGE_SIZE} {
+//!     index: {INDEX_NAME},
+//!     size: {PAGE_SIZE}
+//! });
+//! ```
+//!
 //! ### Replacement values
 //! 
 //! The `json_fn` macro will convert a set of replacement tokens and token tree
 //! and returns a lambda function that substitutes them:
 //! 
 //! ```ignore
-//! // Declares an inline Fn(&str, &str, &str) -> String
+//! // Declares an inline Fn(&str, &str, &str) -> JsonString
 //! let f = json_fn!(|dst, lat, lon| {
 //!     query: {
 //!         filtered: {
@@ -152,9 +178,274 @@
 //! let json = f("\"20km\"", "37.776", "-122.41");
 //! ```
 //! 
-//! All input arguments are a `&str`, and the output is a `String`.
+//! All input arguments are a `&str`, and the output is a `JsonString`.
+//! The body is parsed once, when the lambda is created, rather than each time it's called.
 //! Only simple variable substitution is supported, no repeating or
 //! sanitisation of the replacement values.
+//!
+//! Replacements in key position are the exception.
+//! They're always quoted and escaped as json strings, so runtime field names can be used as keys:
+//!
+//! ```ignore
+//! let f = json_fn!(|field| {
+//!     sort: [
+//!         { $field: { order: "asc" } }
+//!     ]
+//! });
+//!
+//! // Produces {"sort":[{"timestamp":{"order":"asc"}}]}
+//! let json = f("timestamp");
+//! ```
+//!
+//! Replacements can also be interpolated into strings with `${name}`.
+//! The value is escaped, but not quoted:
+//!
+//! ```ignore
+//! let f = json_fn!(|date| {
+//!     index: "logs-${date}"
+//! });
+//!
+//! // Produces {"index":"logs-2016.10.19"}
+//! let json = f("2016.10.19");
+//! ```
+//!
+//! A `$` in a quoted string is never a replacement unless it's followed by `{name}`.
+//! To write a key or string that starts with `$`, like a MongoDB operator, either quote it
+//! or double the `$`:
+//!
+//! ```ignore
+//! let f = json_fn!(|min| {
+//!     age: { $$gt: $min, "$lt": 100 }
+//! });
+//!
+//! // Produces {"age":{"$gt":21,"$lt":100}}
+//! let json = f("21");
+//! ```
+//!
+//! For templates parsed at runtime, a different char can be used for replacements with `parse::ParseOptions::sigil`.
+//!
+//! ### Validated json
+//!
+//! `JsonStr` and `JsonString` are to `str` and `String` what json is to text.
+//! They're guaranteed to contain valid json, so they can be used in signatures
+//! that only accept pre-validated json bodies:
+//!
+//! ```ignore
+//! fn send(body: &JsonStr) {
+//!     // `body` dereferences to a `&str`
+//! }
+//!
+//! send(&json_str!({ query: { match_all: {} } }));
+//! ```
+//!
+//! The output of the macros is validated after it's been sanitised,
+//! and the macros will panic if they produce invalid json.
+//!
+//! `json_fn` splices its values in as-is, so its output is validated each time it's called,
+//! and a call whose values make the json invalid panics.
+//! Strings can also be validated at runtime with `JsonStr::from_str` or `str::parse::<JsonString>`.
+//!
+//! ### Partials
+//!
+//! A `json_fn` can be embedded in another one as a _partial_ with the `@name(args)` syntax,
+//! so common sub-documents only need to be defined once.
+//! The partials a body uses are listed with an `@` after the replacement tokens:
+//!
+//! ```ignore
+//! let geo_filter = json_fn!(|dst, lat, lon| {
+//!     geo_distance: {
+//!         distance: $dst,
+//!         location: {
+//!             lat: $lat,
+//!             lon: $lon
+//!         }
+//!     }
+//! });
+//!
+//! let f = json_fn!(|dst, lat, lon| @geo_filter {
+//!     query: {
+//!         filtered: {
+//!             query: {
+//!                 match_all: {}
+//!             },
+//!             filter: @geo_filter(dst, lat, lon)
+//!         }
+//!     }
+//! });
+//!
+//! let json = f("\"20km\"", "37.776", "-122.41");
+//! ```
+//!
+//! The arguments passed to a partial must be replacement tokens of the outer `json_fn`.
+//!
+//! On `stable`, each `@name(args)` call is matched against the listed partials by name
+//! when the `json_fn` is called.
+//! So every partial a body calls has to be listed with `@name` before it, even though it's already in scope,
+//! and calling one that isn't listed panics.
+//!
+//! ### Including files
+//!
+//! Large queries can be kept in their own files with `include_json` and `include_json_fn`,
+//! which work like `json_lit` and `json_fn`, but read their json from a file relative to the crate root:
+//!
+//! ```ignore
+//! let json = include_json!("queries/match_all.json");
+//!
+//! let f = include_json_fn!(|qry| @geo_filter "queries/search.json");
+//! ```
+//!
+//! Options and constants go before the path, like `include_json!(#[canonical] {PAGE_SIZE} "queries/search.json")`.
+//! The file is embedded with `include_str` at compile time, but it's only sanitised and validated at runtime,
+//! when it's first used, so an invalid file panics then rather than failing to compile.
+//!
+//! ### Newline-delimited json
+//!
+//! The `json_lines` macro builds a body of newline-delimited json, like the ones sent to
+//! Elasticsearch's `_bulk` and `_msearch` endpoints.
+//! Each value is minified onto its own line, and every line ends with a newline:
+//!
+//! ```ignore
+//! // Produces {"index":{"_index":"logs"}}\n{"msg":"a"}\n
+//! let body = json_lines!(
+//!     { index: { _index: "logs" } },
+//!     { msg: "a" }
+//! );
+//! ```
+//!
+//! To stream values from `json_fn` or elsewhere into an `io::Write`, use `ndjson::NdjsonWriter`.
+//! It can also write json text sequences from RFC 7464, where each value starts with a record separator.
+//!
+//! Going the other way, `parse::parse_values` splits a sequence of quasi-json values
+//! and sanitises each one, and `value::Value::parse_sequence` parses a sequence of strict json values.
+//!
+//! ### CBOR and MessagePack
+//!
+//! The `cbor_lit` and `msgpack_lit` macros take the same syntax as `json_str`,
+//! but return the value encoded as a `&'static [u8]`:
+//!
+//! ```ignore
+//! // Produces [0xa1, 0x61, 0x61, 0x01]
+//! let bytes = cbor_lit!({ a: 1 });
+//! ```
+//!
+//! The bytes aren't encoded at compile time. They're encoded at runtime, the first time the macro is evaluated,
+//! and cached in a static from then on, so invalid json panics then, like it does for `json_str`.
+//! At runtime, any `JsonStr` can be encoded with `to_cbor` and `to_msgpack`.
+//!
+//! ### YAML
+//!
+//! The `yaml_str` macro takes the same syntax as `json_str`, but returns block-style YAML,
+//! like for Kubernetes manifests or CI configs:
+//!
+//! ```ignore
+//! // Produces:
+//! // kind: ConfigMap
+//! // data:
+//! //   enabled: "yes"
+//! //   ports:
+//! //     - 80
+//! let yaml = yaml_str!({
+//!     kind: "ConfigMap",
+//!     data: {
+//!         enabled: "yes",
+//!         ports: [80]
+//!     }
+//! });
+//! ```
+//!
+//! Strings that YAML would read as something else, like `"yes"`, are quoted.
+//! At runtime, any `JsonStr` can be converted with `to_yaml`.
+//!
+//! ### Conditional members
+//!
+//! Object members and array elements can have `#[cfg(...)]` attributes.
+//! They're removed if the predicate doesn't match:
+//!
+//! ```ignore
+//! // Produces {"query":{"match_all":{}},"explain":true} in debug builds
+//! let json = json_str!({
+//!     query: { match_all: {} },
+//!     #[cfg(debug_assertions)]
+//!     explain: true
+//! });
+//! ```
+//!
+//! Predicates are checked against `parse::Cfg::current()`, which is the configuration of the target and
+//! whether debug assertions are enabled. Features can't be seen by the macros, so rather than silently
+//! never matching, a `feature` predicate is kept in the output, and the macro panics because it's invalid json.
+//!
+//! ### Options
+//!
+//! Extra sanitisation can be turned on by listing fields of `parse::ParseOptions`
+//! in an attribute before the json.
+//! With `rust_values`, Rust values like `None`, `Some(x)`, tuples, ranges and repeat arrays
+//! are translated into json:
+//!
+//! ```ignore
+//! // Produces {"query":{"bool":{"filter":[{"terms":{"id":[1,2,3]}},{"term":{"deleted":false}}]}}}
+//! let json = json_str!(#[rust_values, expand_key_paths] {
+//!     query.bool.filter: [
+//!         { terms: { id: 1..=3 } },
+//!         { term: { deleted: Some(false) } }
+//!     ]
+//! });
+//! ```
+//!
+//! For `json_fn`, the attribute goes after the replacement tokens, like `json_fn!(|qry| #[rust_values] { ... })`.
+//!
+//! With `extended_json`, MongoDB shell calls like `ObjectId("...")` and `NumberLong(5)` are written as
+//! canonical Extended JSON, and operator keys like `$gt` are written as keys instead of replacements.
+//! A call with the wrong arguments, like `ObjectId(5)` or an impossible date, makes the macro panic.
+//! In `json_fn`, a `$name` key is a replacement if it's one of the args, even if it's named like an operator,
+//! and an operator otherwise:
+//!
+//! ```ignore
+//! // Produces {"_id":{"$oid":"507f1f77bcf86cd799439011"},"age":{"$gt":{"$numberLong":"21"}}}
+//! let json = json_str!(#[extended_json] {
+//!     _id: ObjectId("507f1f77bcf86cd799439011"),
+//!     age: { $gt: NumberLong(21) }
+//! });
+//! ```
+//!
+//! With `canonical`, the json is written in its canonical form from RFC 8785,
+//! with keys sorted and numbers in their shortest form, so it can be hashed or signed:
+//!
+//! ```ignore
+//! // Produces {"amount":1000,"currency":"AUD"}
+//! let json = json_str!(#[canonical] { currency: "AUD", amount: 1e3 });
+//! ```
+//!
+//! Any `JsonStr` can be canonicalised at runtime with `to_canonical`.
+//!
+//! ### Duplicate keys
+//!
+//! An object can't have the same key more than once.
+//! The macros check for duplicate keys when they're evaluated, so a duplicate key panics at runtime
+//! rather than failing to compile. `json_fn` checks its body once, when the fn is created.
+//! Replacements in key position count as keys named after the replacement, so `{ $k: 1, $k: 2 }` is also rejected.
+//!
+//! At runtime, `JsonStr::duplicate_keys` finds any duplicate keys in json from elsewhere.
+//! The `parse::ParseOptions::dedup_keys` option removes them instead, keeping either the first
+//! or last member with a key.
+//!
+//! ### Elasticsearch queries
+//!
+//! With the `elasticsearch` feature, the `elasticsearch` option checks that the json is a valid search body.
+//! Queries, `bool` sections and aggregation types are checked against a description of the query DSL that
+//! ships with this crate, as are the parameters aggregations require:
+//!
+//! ```ignore
+//! // Panics with: json_str produced invalid json: unknown query 'match_al' at '/query', did you mean 'match_all'?
+//! let json = json_str!(#[elasticsearch] {
+//!     query: { match_al: {} }
+//! });
+//! ```
+//!
+//! The check runs when the macro's json is built, at runtime, so an invalid body panics then rather than
+//! failing to compile. Only the nightly plugin checks bodies at compile time.
+//! `elasticsearch::validate` checks json from elsewhere the same way.
+//!
+//! Setting the option without the `elasticsearch` feature also panics.
 
 #![doc(html_root_url = "http://kodraus.github.io/rustdoc/json_str/")]
 #![cfg_attr(feature = "nightly", crate_type="dylib")]
@@ -162,6 +453,25 @@
 
 /// Raw parsers for sanitising a stream of json.
 pub mod parse;
+/// Reusable templates that can be embedded in other templates.
+pub mod partial;
+/// Render parsed fragments with runtime replacement values.
+pub mod fragments;
+/// Sets of json templates loaded from files at runtime.
+pub mod templates;
+/// Compile json templates into Rust code from a `build.rs` script.
+pub mod build;
+/// Strict json values.
+pub mod value;
+/// Write newline-delimited json, like the bodies of Elasticsearch bulk requests, and json text sequences.
+pub mod ndjson;
+/// Validate Elasticsearch search bodies against the query DSL.
+#[cfg(feature = "elasticsearch")]
+pub mod elasticsearch;
+
+mod string;
+
+pub use string::{JsonStr, JsonString};
 
 #[cfg(feature = "nightly")]
 include!("lib.rs.in");
@@ -169,48 +479,285 @@ include!("lib.rs.in");
 #[cfg_attr(not(feature = "nightly"), macro_export)]
 #[cfg(not(feature = "nightly"))]
 macro_rules! json_str {
-    ($j:tt) => ({
+    (#[$($opt:ident),*] $j:tt) => ({
         let json_raw = stringify!($j);
         let mut json = String::with_capacity(json_raw.len());
 
-        $crate::parse::parse_literal(json_raw.as_bytes(), &mut json);
+        let opts = $crate::parse::ParseOptions {
+            $($opt: true,)*
+            ..$crate::parse::ParseOptions::strict()
+        };
+
+        $crate::parse::parse_literal_with(json_raw.as_bytes(), &mut json, &opts);
+
+        let json = $crate::JsonString::from_string(json).unwrap_or_else(|e| panic!("json_str produced invalid json: {}", e));
+
+        if let Some(e) = json.duplicate_keys().into_iter().next() {
+            panic!("json_str produced invalid json: {}", e);
+        }
+
+        if let Err(e) = opts.validate(&json) {
+            panic!("json_str produced invalid json: {}", e);
+        }
+
+        if opts.canonical {
+            json.to_canonical().unwrap_or_else(|e| panic!("json_str produced invalid canonical json: {}", e))
+        }
+        else {
+            json
+        }
+    });
+    ($j:tt) => (json_str!(#[] $j))
+}
+
+#[cfg_attr(not(feature = "nightly"), macro_export)]
+#[cfg(not(feature = "nightly"))]
+macro_rules! cbor_lit {
+    (# $opts:tt $j:tt) => ({
+        static BYTES: ::std::sync::OnceLock<Vec<u8>> = ::std::sync::OnceLock::new();
+
+        let bytes: &'static [u8] = BYTES.get_or_init(|| json_str!(# $opts $j).to_cbor());
+        bytes
+    });
+    ($j:tt) => (cbor_lit!(#[] $j))
+}
+
+#[cfg_attr(not(feature = "nightly"), macro_export)]
+#[cfg(not(feature = "nightly"))]
+macro_rules! msgpack_lit {
+    (# $opts:tt $j:tt) => ({
+        static BYTES: ::std::sync::OnceLock<Vec<u8>> = ::std::sync::OnceLock::new();
+
+        let bytes: &'static [u8] = BYTES.get_or_init(|| json_str!(# $opts $j).to_msgpack());
+        bytes
+    });
+    ($j:tt) => (msgpack_lit!(#[] $j))
+}
+
+#[cfg_attr(not(feature = "nightly"), macro_export)]
+#[cfg(not(feature = "nightly"))]
+macro_rules! yaml_str {
+    (# $opts:tt $j:tt) => (json_str!(# $opts $j).to_yaml());
+    ($j:tt) => (yaml_str!(#[] $j))
+}
+
+#[cfg_attr(not(feature = "nightly"), macro_export)]
+#[cfg(not(feature = "nightly"))]
+macro_rules! json_lines {
+    (# $opts:tt $($j:tt),+ $(,)*) => ({
+        let mut lines = String::new();
+
+        $(
+            lines.push_str(&json_str!(# $opts $j));
+            lines.push('\n');
+        )+
+
+        lines
+    });
+    ($($j:tt),+ $(,)*) => (json_lines!(#[] $($j),+))
+}
+
+/// Build a `&'static JsonStr` from a json literal.
+///
+/// Constants are listed before the body, each in braces and separated by commas,
+/// and referred to in the body with `{NAME}`:
+///
+/// ```ignore
+/// let json = json_lit!(#[canonical] {INDEX_NAME}, {PAGE_SIZE} {
+///     index: {INDEX_NAME},
+///     size: {PAGE_SIZE}
+/// });
+/// ```
+///
+/// The json is built at runtime, the first time the macro is evaluated, and cached in a static from then on.
+/// Constants are written with `templates::TemplateValue` at that point, so they're never baked in at compile time,
+/// and a body or constant that produces invalid json panics then.
+#[cfg_attr(not(feature = "nightly"), macro_export)]
+#[cfg(not(feature = "nightly"))]
+macro_rules! json_lit {
+    (@consts $src:ident [$($opt:ident),*] [$($c:ident)*] {$next:ident}, $($rest:tt)+) => (json_lit!(@consts $src [$($opt),*] [$($c)* $next] $($rest)+));
+    (@consts $src:ident [$($opt:ident),*] [$($c:ident)*] {$next:ident} $j:tt) => (json_lit!(@lit [$($opt),*] [$($c)* $next] $src!($j)));
+    (@consts $src:ident [$($opt:ident),*] [$($c:ident)*] $j:tt) => (json_lit!(@lit [$($opt),*] [$($c)*] $src!($j)));
+    (@lit [$($opt:ident),*] [$($c:ident)*] $json_raw:expr) => ({
+        static JSON: ::std::sync::OnceLock<$crate::JsonString> = ::std::sync::OnceLock::new();
+
+        let json: &'static $crate::JsonStr = JSON.get_or_init(|| {
+            let json_raw: &str = $json_raw;
+
+            let mut fragments = Vec::new();
+            let mut result = String::with_capacity(json_raw.len());
+
+            let opts = $crate::parse::ParseOptions {
+                $($opt: true,)*
+                replacements: Some(Vec::new()),
+                ..$crate::parse::ParseOptions::strict()
+            };
+
+            $crate::parse::parse_fragments_with(json_raw.as_bytes(), &mut fragments, &opts);
+
+            if let Some(e) = $crate::parse::duplicate_key(&fragments) {
+                panic!("json_lit produced invalid json: {}", e);
+            }
+
+            for f in fragments {
+                match f {
+                    $crate::parse::JsonFragment::Literal(ref l) => result.push_str(l),
+                    $crate::parse::JsonFragment::Const(ref c) => {
+                        $(
+                            if *c == stringify!($c) {
+                                $crate::templates::TemplateValue::write_json(&$c, &mut result);
+                                continue;
+                            }
+                        )*
+
+                        panic!("constant '{}' is not in the list of constants", c);
+                    },
+                    f => panic!("json_lit can only contain literals and constants, found {:?}", f)
+                }
+            }
+
+            let json = $crate::JsonString::from_string(result).unwrap_or_else(|e| panic!("json_lit produced invalid json: {}", e));
+
+            if let Err(e) = opts.validate(&json) {
+                panic!("json_lit produced invalid json: {}", e);
+            }
+
+            if opts.canonical {
+                json.to_canonical().unwrap_or_else(|e| panic!("json_lit produced invalid canonical json: {}", e))
+            }
+            else {
+                json
+            }
+        });
 
         json
-    })
+    });
+    (#[$($opt:ident),*] $($j:tt)+) => (json_lit!(@consts stringify [$($opt),*] [] $($j)+));
+    ($($j:tt)+) => (json_lit!(@consts stringify [] [] $($j)+))
+}
+
+#[cfg_attr(not(feature = "nightly"), macro_export)]
+#[cfg(not(feature = "nightly"))]
+macro_rules! include_json {
+    (#[$($opt:ident),*] $($j:tt)+) => (json_lit!(@consts json_str_include_file [$($opt),*] [] $($j)+));
+    ($($j:tt)+) => (json_lit!(@consts json_str_include_file [] [] $($j)+))
+}
+
+#[cfg_attr(not(feature = "nightly"), macro_export)]
+#[cfg(not(feature = "nightly"))]
+macro_rules! include_json_fn {
+    (|$($repl:ident),*| #[$($opt:ident),*] $($j:tt)+) => (json_fn!(@partials json_str_include_file [$($repl),*] [$($opt),*] [] $($j)+));
+    (|$($repl:ident),*| $($j:tt)+) => (json_fn!(@partials json_str_include_file [$($repl),*] [] [] $($j)+))
+}
+
+#[doc(hidden)]
+#[cfg_attr(not(feature = "nightly"), macro_export)]
+#[cfg(not(feature = "nightly"))]
+macro_rules! json_str_include_file {
+    ($path:tt) => (include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/", $path)))
 }
 
 #[cfg_attr(not(feature = "nightly"), macro_export)]
 #[cfg(not(feature = "nightly"))]
 macro_rules! json_fn {
-    (|$($repl:ident),*| $j:tt) => (|$($repl),*| {
-        let repls = {
-            let mut repls = ::std::collections::BTreeMap::<&'static str, &str>::new();
+    (|$($repl:ident),*| #[$($opt:ident),*] $($j:tt)+) => (json_fn!(@partials stringify [$($repl),*] [$($opt),*] [] $($j)+));
+    (|$($repl:ident),*| $($j:tt)+) => (json_fn!(@partials stringify [$($repl),*] [] [] $($j)+));
+    (@partials $src:ident [$($repl:ident),*] [$($opt:ident),*] [$($partial:ident)*] @$next:ident, $($j:tt)+) => (json_fn!(@partials $src [$($repl),*] [$($opt),*] [$($partial)* $next] $($j)+));
+    (@partials $src:ident [$($repl:ident),*] [$($opt:ident),*] [$($partial:ident)*] @$next:ident $j:tt) => (json_fn!(@fn [$($repl),*] [$($opt),*] [$($partial)* $next] $src!($j)));
+    (@partials $src:ident [$($repl:ident),*] [$($opt:ident),*] [$($partial:ident)*] $j:tt) => (json_fn!(@fn [$($repl),*] [$($opt),*] [$($partial)*] $src!($j)));
+    (@fn [$($repl:ident),*] [$($opt:ident),*] [$($partial:ident)*] $json_raw:expr) => ({
+        let json_raw: &'static str = $json_raw;
 
-            $(repls.insert(stringify!($repl), $repl);)*
+        let mut fragments = Vec::new();
 
-            repls
+        let opts = $crate::parse::ParseOptions {
+            $($opt: true,)*
+            replacements: Some(vec![$(stringify!($repl).to_owned()),*]),
+            ..$crate::parse::ParseOptions::strict()
         };
 
-        let json_raw = stringify!($j);
+        $crate::parse::parse_fragments_with(json_raw.as_bytes(), &mut fragments, &opts);
 
-        let mut fragments = Vec::new();
-        let mut result = String::new();
-        
-        $crate::parse::parse_fragments(json_raw.as_bytes(), &mut fragments);
-
-        for f in fragments {
-            match f {
-                $crate::parse::JsonFragment::Literal(ref l) => result.push_str(l),
-                $crate::parse::JsonFragment::Repl(ref r) => {
-                    let val = repls
-                        .get(r)
-                        .expect(&format!("replacement '{}' is not in the list of fn args", r));
-
-                    result.push_str(val);
+        if let Some(e) = $crate::parse::duplicate_key(&fragments) {
+            panic!("json_fn produced invalid json: {}", e);
+        }
+
+        //The template is only parsed once, so partials are borrowed rather than moved into the fn
+        $(let $partial = &$partial;)*
+
+        move |$($repl),*| {
+            let repls = {
+                let mut repls = ::std::collections::BTreeMap::<&'static str, &str>::new();
+
+                $(repls.insert(stringify!($repl), $repl);)*
+
+                repls
+            };
+
+            let mut result = String::new();
+
+            for f in &fragments {
+                match *f {
+                    $crate::parse::JsonFragment::Literal(ref l) => result.push_str(l),
+                    $crate::parse::JsonFragment::Repl(r) => {
+                        let val = repls
+                            .get(r)
+                            .expect(&format!("replacement '{}' is not in the list of fn args", r));
+
+                        result.push_str(val);
+                    },
+                    $crate::parse::JsonFragment::KeyRepl(r) => {
+                        let val = repls
+                            .get(r)
+                            .expect(&format!("replacement '{}' is not in the list of fn args", r));
+
+                        result.push('"');
+                        $crate::parse::escape(val, &mut result);
+                        result.push('"');
+                    },
+                    $crate::parse::JsonFragment::StrRepl(r) => {
+                        let val = repls
+                            .get(r)
+                            .expect(&format!("replacement '{}' is not in the list of fn args", r));
+
+                        $crate::parse::escape(val, &mut result);
+                    },
+                    $crate::parse::JsonFragment::Partial(p, ref args) => {
+                        let args: Vec<&str> = args
+                            .iter()
+                            .map(|a| *repls
+                                .get(a)
+                                .expect(&format!("replacement '{}' is not in the list of fn args", a)))
+                            .collect();
+
+                        $(
+                            if p == stringify!($partial) {
+                                $crate::partial::Partial::render($partial, &args, &mut result);
+                                continue;
+                            }
+                        )*
+
+                        panic!("partial '{}' is not in the list of partials", p);
+                    },
+                    $crate::parse::JsonFragment::Const(c) => {
+                        panic!("constant '{}' can't be used in json_fn, pass it as an argument instead", c);
+                    }
                 }
             }
-        }
 
-        result
+            //Values are spliced in as-is, so the result is validated on each call
+            let json = $crate::JsonString::from_string(result).unwrap_or_else(|e| panic!("json_fn produced invalid json: {}", e));
+
+            if let Err(e) = opts.validate(&json) {
+                panic!("json_fn produced invalid json: {}", e);
+            }
+
+            if opts.canonical {
+                json.to_canonical().unwrap_or_else(|e| panic!("json_fn produced invalid canonical json: {}", e))
+            }
+            else {
+                json
+            }
+        }
     })
 }
\ No newline at end of file
@@ -0,0 +1,143 @@
+use std::io::{self, Write};
+
+use parse;
+use JsonStr;
+
+/// Write json values as newline-delimited json, one value per line.
+///
+/// Each value is minified before it's written, so it only takes up a single line,
+/// and is followed by a newline.
+/// This is the format used by the bodies of Elasticsearch's `_bulk` and `_msearch` endpoints.
+///
+/// # Examples
+///
+/// ```
+/// use json_str::JsonStr;
+/// use json_str::ndjson::NdjsonWriter;
+///
+/// let mut writer = NdjsonWriter::new(Vec::new());
+///
+/// writer.write_json(JsonStr::from_str("{ \"index\": { \"_index\": \"logs\" } }").unwrap()).unwrap();
+/// writer.write_json(JsonStr::from_str("{ \"msg\": \"a\" }").unwrap()).unwrap();
+///
+/// let body = writer.into_inner();
+///
+/// assert_eq!(&b"{\"index\":{\"_index\":\"logs\"}}\n{\"msg\":\"a\"}\n"[..], &body[..]);
+/// ```
+///
+/// Use `NdjsonWriter::json_seq` to write a json text sequence from RFC 7464 instead,
+/// where each line also starts with a record separator.
+#[derive(Debug)]
+pub struct NdjsonWriter<W> {
+    writer: W,
+    line: String,
+    record_separator: bool
+}
+
+impl<W> NdjsonWriter<W>
+    where W: Write
+{
+    /// Create a writer that writes lines to the given `io::Write`.
+    pub fn new(writer: W) -> Self {
+        NdjsonWriter {
+            writer,
+            line: String::new(),
+            record_separator: false
+        }
+    }
+
+    /// Create a writer that writes a json text sequence to the given `io::Write`.
+    pub fn json_seq(writer: W) -> Self {
+        NdjsonWriter {
+            writer,
+            line: String::new(),
+            record_separator: true
+        }
+    }
+
+    /// Write a json value as a single line.
+    pub fn write_json(&mut self, json: &JsonStr) -> io::Result<()> {
+        //Only json with whitespace somewhere might need minifying
+        let line = if json.bytes().any(|b| matches!(b, b' ' | b'\t' | b'\n' | b'\r')) {
+            self.line.clear();
+            minify(json.as_str(), &mut self.line);
+
+            &self.line
+        }
+        else {
+            json.as_str()
+        };
+
+        if self.record_separator {
+            self.writer.write_all(&[parse::RECORD_SEPARATOR as u8])?;
+        }
+
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.write_all(b"\n")
+    }
+
+    /// Write each json value as a single line.
+    pub fn write_all<I, J>(&mut self, lines: I) -> io::Result<()>
+        where I: IntoIterator<Item = J>,
+              J: AsRef<JsonStr>
+    {
+        for json in lines {
+            self.write_json(json.as_ref())?;
+        }
+
+        Ok(())
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Get a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Get a mutable reference to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Get the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// Remove whitespace between the tokens of valid json.
+//
+// The json is already valid, so this doesn't sanitise it like `parse::parse_literal` does.
+// Strings are copied as they are.
+fn minify(json: &str, minified: &mut String) {
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (i, b) in json.bytes().enumerate() {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => ()
+            }
+        }
+        else {
+            match b {
+                b'"' => in_string = true,
+                b' ' | b'\t' | b'\n' | b'\r' => {
+                    minified.push_str(&json[start..i]);
+                    start = i + 1;
+                },
+                _ => ()
+            }
+        }
+    }
+
+    minified.push_str(&json[start..]);
+}
@@ -1,160 +1,1775 @@
+use std::error::Error;
+use std::fmt::{self, Write};
 use std::str;
 
+use value::{DuplicateKey, Keep, Value};
+use JsonStr;
+
 /// A fragment of json.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum JsonFragment<'a> {
     Literal(String),
-    Repl(&'a str)
+    Repl(&'a str),
+    /// A replacement in key position, like `$field: 1`.
+    /// The value is quoted and escaped as a json string.
+    KeyRepl(&'a str),
+    /// A replacement interpolated into a string, like `"logs-${date}"`.
+    /// The value is escaped, but not quoted.
+    StrRepl(&'a str),
+    /// A partial template called with a list of replacement idents, like `@name(arg1, arg2)`.
+    Partial(&'a str, Vec<&'a str>),
+    /// A reference to a constant, like `{PAGE_SIZE}`.
+    /// The value of the constant is spliced in as json.
+    Const(&'a str)
+}
+
+/// Options for sanitising json.
+///
+/// The default options only minify the input and standardise quotes.
+/// Use `ParseOptions::strict()` for options that make sure the output is strict json.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Escape control characters inside strings, like raw newlines and tabs.
+    pub escape_control_chars: bool,
+    /// Expand keys written as dotted paths, like `query.bool.filter: []`, into nested objects.
+    ///
+    /// Objects with the same key are merged, so paths that share a prefix end up in the same object.
+    /// Only input that's valid json after sanitising is expanded.
+    pub expand_key_paths: bool,
+    /// Translate Rust values into json.
+    ///
+    /// `None` becomes `null` and `Some(x)` becomes `x`.
+    /// Tuples like `(1, 2)` and integer ranges like `1..=3` become arrays,
+    /// and repeat arrays like `[0; 4]` are expanded, along with any replacements in them, like `[$x; 2]`.
+    pub rust_values: bool,
+    /// The options that `#[cfg(...)]` attributes on members and elements are checked against.
+    ///
+    /// Members and elements whose predicate doesn't match are removed.
+    /// Other attributes are removed without affecting what they're on.
+    pub cfg: Cfg,
+    /// Write canonical json, per RFC 8785, with sorted keys and numbers in their shortest form.
+    ///
+    /// Fragments can't be canonicalised until their replacements have values, so `json_fn` and `json_lit`
+    /// canonicalise their output instead.
+    /// Input that isn't valid json after sanitising, or has numbers that can't be represented by a double,
+    /// is left as is. The macros check their output again and panic in that case.
+    pub canonical: bool,
+    /// Remove members whose key is already used by another member of the same object,
+    /// keeping either the first or the last one.
+    ///
+    /// This is useful for normalising json from elsewhere, like fixture files.
+    /// Only literals are deduplicated, and input that isn't valid json after sanitising is left as is.
+    pub dedup_keys: Option<Keep>,
+    /// Write MongoDB shell calls, like `ObjectId("...")`, `ISODate("...")` and `NumberLong(5)`,
+    /// as canonical Extended JSON, like `{"$oid":"..."}`.
+    /// A call whose arguments have the wrong type or number, like `ObjectId(5)` or `ISODate("2020-02-30")`, panics.
+    ///
+    /// Operator keys, like `$gt` or `$set`, are also written as keys instead of being treated as replacements.
+    /// Any `$name` key is an operator in literals. Fragments can have key replacements,
+    /// so a `$name` key there is an operator unless it's one of the `replacements`.
+    /// If they aren't known then only well-known operators are keys.
+    /// A call can take a replacement, like `ObjectId($id)`, whose value is used as-is.
+    pub extended_json: bool,
+    /// The names of the replacements a template takes, like the args of a `json_fn`.
+    ///
+    /// With `extended_json`, a `$name` key that's one of these is always a replacement,
+    /// even if it's named like an operator, and any other `$name` key is an operator.
+    pub replacements: Option<Vec<String>>,
+    /// The char that starts a replacement, like the `$` in `$qry` or `${date}`.
+    ///
+    /// This is `$` by default, and should be an ASCII char that can't start a json value, like `%`.
+    /// Non-ASCII chars aren't supported, so `$` is used instead.
+    /// Whatever the sigil is, doubling it escapes it, so `$$gt: 1` is the key `"$gt"`
+    /// and `"$${date}"` is the string `"${date}"`.
+    pub sigil: Option<char>,
+    /// Check that the json is a valid Elasticsearch search body, with known queries and aggregations.
+    ///
+    /// This is only checked by the macros, after sanitising, with `ParseOptions::validate`.
+    /// It needs the `elasticsearch` feature, and `validate` returns an error if it's set without it.
+    pub elasticsearch: bool
+}
+
+impl ParseOptions {
+    /// Options for producing strict json.
+    ///
+    /// These are the options used by the `json_str`, `json_lit` and `json_fn` macros.
+    pub fn strict() -> Self {
+        ParseOptions {
+            escape_control_chars: true,
+            cfg: Cfg::current(),
+            ..ParseOptions::default()
+        }
+    }
+
+    /// Check sanitised json against the validation these options turn on.
+    ///
+    /// With the `elasticsearch` feature, the `elasticsearch` option checks that the json is a valid search body.
+    /// Without it, setting that option is an error.
+    pub fn validate(&self, json: &JsonStr) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.elasticsearch {
+            #[cfg(feature = "elasticsearch")]
+            ::elasticsearch::validate(json)?;
+
+            #[cfg(not(feature = "elasticsearch"))]
+            return Err("the elasticsearch option needs the elasticsearch feature".into());
+        }
+
+        let _ = json;
+        Ok(())
+    }
+
+    // The replacements a `$name` key could be, if they're known.
+    fn key_replacements(&self, break_on_repl: bool) -> Option<&[String]> {
+        if break_on_repl {
+            self.replacements.as_deref()
+        }
+        else {
+            Some(&[])
+        }
+    }
+
+    // The byte that starts a replacement.
+    fn sigil(&self) -> u8 {
+        match self.sigil {
+            Some(c) if c.is_ascii() => c as u8,
+            _ => b'$'
+        }
+    }
+}
+
+/// A set of configuration options that `#[cfg(...)]` attributes are checked against.
+///
+/// Options are either names, like `unix`, or key-value pairs, like `feature = "nightly"`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cfg {
+    options: Vec<(String, Option<String>)>,
+    unknown_features: bool
+}
+
+macro_rules! set_cfg_values {
+    ($cfg:ident, $name:ident: $($value:tt),*) => {
+        $(
+            if cfg!($name = $value) {
+                $cfg.set_value(stringify!($name), $value);
+            }
+        )*
+    }
+}
+
+impl Cfg {
+    /// An empty set of options.
+    pub fn new() -> Self {
+        Cfg::default()
+    }
+
+    /// The options this crate was built with.
+    ///
+    /// These are `debug_assertions` and the options for the target, like `unix` or `target_os = "linux"`.
+    /// Features are different for each crate, so they can't be known here.
+    /// Rather than never matching, predicates that check a feature are malformed against these options.
+    pub fn current() -> Self {
+        let mut cfg = Cfg {
+            unknown_features: true,
+            ..Cfg::new()
+        };
+
+        if cfg!(debug_assertions) {
+            cfg.set("debug_assertions");
+        }
+        if cfg!(unix) {
+            cfg.set("unix");
+        }
+        if cfg!(windows) {
+            cfg.set("windows");
+        }
+
+        set_cfg_values!(cfg, target_family: "unix", "windows", "wasm");
+        set_cfg_values!(cfg, target_os: "linux", "macos", "ios", "android", "windows", "freebsd", "netbsd",
+            "openbsd", "dragonfly", "solaris", "illumos", "fuchsia", "redox", "haiku", "wasi", "emscripten", "none");
+        set_cfg_values!(cfg, target_arch: "x86", "x86_64", "arm", "aarch64", "wasm32", "wasm64", "mips", "mips64",
+            "powerpc", "powerpc64", "riscv32", "riscv64", "s390x", "sparc64", "loongarch64");
+        set_cfg_values!(cfg, target_env: "gnu", "musl", "msvc", "sgx");
+        set_cfg_values!(cfg, target_vendor: "apple", "pc", "unknown", "fortanix");
+        set_cfg_values!(cfg, target_pointer_width: "16", "32", "64");
+        set_cfg_values!(cfg, target_endian: "little", "big");
+        set_cfg_values!(cfg, panic: "unwind", "abort");
+
+        cfg
+    }
+
+    /// Set a name, like `unix`.
+    pub fn set(&mut self, name: &str) -> &mut Self {
+        self.options.push((name.to_owned(), None));
+        self
+    }
+
+    /// Set a key-value pair, like `feature = "nightly"`.
+    pub fn set_value(&mut self, name: &str, value: &str) -> &mut Self {
+        self.options.push((name.to_owned(), Some(value.to_owned())));
+        self
+    }
+
+    /// Check whether a predicate, like `all(unix, feature = "nightly")`, matches the options.
+    ///
+    /// Returns `None` if the predicate is malformed, or checks features that aren't known,
+    /// like against `Cfg::current()`.
+    pub fn matches(&self, predicate: &str) -> Option<bool> {
+        let (rest, matches) = self.predicate(predicate.as_bytes())?;
+
+        if shift_while(rest, is_whitespace).is_empty() {
+            Some(matches)
+        }
+        else {
+            None
+        }
+    }
+
+    fn predicate<'a>(&self, remainder: &'a [u8]) -> Option<(&'a [u8], bool)> {
+        let remainder = shift_while(remainder, is_whitespace);
+        let (remainder, name) = take_while(remainder, (), |_, c| {
+            ((), is_ident(c))
+        });
+
+        if name.is_empty() {
+            return None;
+        }
+
+        let remainder = shift_while(remainder, is_whitespace);
+
+        match (name, remainder.first()) {
+            ("all", Some(&b'(')) | ("any", Some(&b'(')) | ("not", Some(&b'(')) => {
+                let mut remainder = &remainder[1..];
+                let mut results = Vec::new();
+
+                loop {
+                    remainder = shift_while(remainder, |c| is_whitespace(c) || c == b',');
+
+                    if remainder.first() == Some(&b')') {
+                        remainder = &remainder[1..];
+                        break;
+                    }
+
+                    let (rest, matches) = self.predicate(remainder)?;
+
+                    results.push(matches);
+                    remainder = rest;
+                }
+
+                let matches = match name {
+                    "all" => results.iter().all(|m| *m),
+                    "any" => results.iter().any(|m| *m),
+                    _ if results.len() == 1 => !results[0],
+                    _ => return None
+                };
+
+                Some((remainder, matches))
+            },
+            (_, Some(&b'=')) => {
+                let remainder = shift_while(&remainder[1..], is_whitespace);
+                if remainder.first() != Some(&b'"') {
+                    return None;
+                }
+
+                let (rest, value) = take_while(&remainder[1..], (), |_, c| {
+                    ((), c != b'"')
+                });
+
+                if rest.first() != Some(&b'"') || (name == "feature" && self.unknown_features) {
+                    return None;
+                }
+
+                let matches = self.options.iter().any(|(n, v)| n == name && v.as_deref() == Some(value));
+
+                Some((&rest[1..], matches))
+            },
+            ("true", _) => Some((remainder, true)),
+            ("false", _) => Some((remainder, false)),
+            _ => {
+                let matches = self.options.iter().any(|(n, v)| n == name && v.is_none());
+
+                Some((remainder, matches))
+            }
+        }
+    }
 }
 
 /// Parse and sanitise the complete sequence as a literal.
 pub fn parse_literal(remainder: &[u8], json: &mut String) {
-    let _ = literal(remainder, json, false);
+    parse_literal_with(remainder, json, &ParseOptions::default())
+}
+
+/// Parse and sanitise the complete sequence as a literal with the given options.
+pub fn parse_literal_with(remainder: &[u8], json: &mut String, opts: &ParseOptions) {
+    let start = json.len();
+    let _ = literal(remainder, json, opts, false);
+
+    //Calls are translated before Rust values, which would turn their parens into arrays
+    if opts.extended_json {
+        let translated = ExtendedJson::default().translate(&json[start..]);
+
+        json.truncate(start);
+        json.push_str(&translated);
+    }
+
+    if opts.rust_values {
+        let translated = RustValues::default().translate(&json[start..]);
+
+        json.truncate(start);
+        json.push_str(&translated);
+    }
+
+    if opts.expand_key_paths {
+        if let Ok(value) = Value::parse(&json[start..]) {
+            json.truncate(start);
+            value.expand_key_paths().write(json);
+        }
+    }
+
+    if let Some(keep) = opts.dedup_keys {
+        if let Ok(value) = Value::parse(&json[start..]) {
+            json.truncate(start);
+            value.dedup_keys(keep).write(json);
+        }
+    }
+
+    if opts.canonical {
+        let mut canonical = String::new();
+
+        if let Ok(Ok(())) = Value::parse(&json[start..]).map(|value| value.write_canonical(&mut canonical)) {
+            json.truncate(start);
+            json.push_str(&canonical);
+        }
+    }
+}
+
+/// Parse and sanitise the complete sequence as a single value with the given options.
+///
+/// `parse_literal_with` runs multiple top-level values together, so `1 2` becomes `12`.
+/// This fails instead if there's more than one value.
+pub fn parse_value_with(remainder: &[u8], json: &mut String, opts: &ParseOptions) -> Result<(), MultipleValues> {
+    let mut values = Vec::new();
+    parse_values_with(remainder, &mut values, opts);
+
+    if values.len() > 1 {
+        return Err(MultipleValues {
+            count: values.len()
+        });
+    }
+
+    json.extend(values);
+
+    Ok(())
+}
+
+/// Parse and sanitise a sequence of top-level values.
+pub fn parse_values(remainder: &[u8], values: &mut Vec<String>) {
+    parse_values_with(remainder, values, &ParseOptions::default())
+}
+
+/// Parse and sanitise a sequence of top-level values with the given options.
+///
+/// Values can be separated by whitespace, like newline-delimited json,
+/// or by the record separators of a json text sequence from RFC 7464.
+/// Each value is sanitised on its own.
+pub fn parse_values_with(remainder: &[u8], values: &mut Vec<String>, opts: &ParseOptions) {
+    let mut remainder = remainder;
+
+    while !remainder.is_empty() {
+        let (value, rest) = split_value(remainder);
+        remainder = rest;
+
+        let mut json = String::new();
+        parse_literal_with(value, &mut json, opts);
+
+        //Values removed by a `cfg` attribute don't count
+        if !json.is_empty() {
+            values.push(json);
+        }
+    }
+}
+
+/// The record separator that starts each value in a json text sequence from RFC 7464.
+pub const RECORD_SEPARATOR: char = '\u{1e}';
+
+// Split the first top-level value off a sequence of values.
+// A value ends at whitespace or a record separator outside of any brackets,
+// or when its outermost brackets or string are closed.
+fn split_value(remainder: &[u8]) -> (&[u8], &[u8]) {
+    let remainder = shift_while(remainder, |c| is_whitespace(c) || c == RECORD_SEPARATOR as u8);
+
+    let mut depth = 0usize;
+    let mut i = 0;
+
+    while i < remainder.len() {
+        match remainder[i] {
+            //Attributes belong to the value after them
+            b'#' if depth == 0 && attribute(&remainder[i + 1..]).is_some() => {
+                let (rest, _) = attribute(&remainder[i + 1..]).unwrap_or((&[], ""));
+
+                i = remainder.len() - shift_while(rest, is_whitespace).len();
+                continue;
+            },
+            //Raw strings don't have escapes, so they're skipped as a whole
+            //The `b` of a raw byte string is skipped like any other char
+            b'r' if raw_string(&remainder[i + 1..]).is_some() => {
+                let (rest, _) = raw_string(&remainder[i + 1..]).unwrap_or((&[], ""));
+
+                i = remainder.len() - rest.len();
+
+                if depth == 0 {
+                    return remainder.split_at(i);
+                }
+
+                continue;
+            },
+            quote @ b'"' | quote @ b'\'' if quote == b'"' || !is_lifetime(&remainder[i + 1..]) => {
+                i += 1;
+
+                while i < remainder.len() && remainder[i] != quote {
+                    if remainder[i] == b'\\' {
+                        i += 1;
+                    }
+
+                    i += 1;
+                }
+
+                if depth == 0 {
+                    let end = (i + 1).min(remainder.len());
+                    return remainder.split_at(end);
+                }
+            },
+            b'{'|b'['|b'(' => depth += 1,
+            b'}'|b']'|b')' => {
+                depth = depth.saturating_sub(1);
+
+                if depth == 0 {
+                    return remainder.split_at(i + 1);
+                }
+            },
+            c if depth == 0 && (is_whitespace(c) || c == RECORD_SEPARATOR as u8) => return remainder.split_at(i),
+            _ => ()
+        }
+
+        i += 1;
+    }
+
+    (remainder, &[])
+}
+
+/// A sequence has more than one top-level value where a single value was expected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultipleValues {
+    count: usize
 }
 
+impl MultipleValues {
+    /// The number of values in the sequence.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl fmt::Display for MultipleValues {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected a single json value, found {}", self.count)
+    }
+}
+
+impl Error for MultipleValues {}
+
 /// Parse and sanitise the complete sequence as literals and replacements.
 pub fn parse_fragments<'a>(remainder: &'a [u8], fragments: &mut Vec<JsonFragment<'a>>) {
-    // Parse a literal
-    let mut l = String::new();
-    let remainder = literal(remainder, &mut l, true);
-    if l.len() > 0 {
-        fragments.push(JsonFragment::Literal(l));
+    parse_fragments_with(remainder, fragments, &ParseOptions::default())
+}
+
+/// Parse and sanitise the complete sequence as literals and replacements with the given options.
+pub fn parse_fragments_with<'a>(remainder: &'a [u8], fragments: &mut Vec<JsonFragment<'a>>, opts: &ParseOptions) {
+    let start = fragments.len();
+    parse_fragments_in(remainder, None, fragments, opts);
+
+    //Calls are translated before Rust values, which would turn their parens into arrays
+    if opts.extended_json {
+        let mut extended_json = ExtendedJson::default();
+
+        for f in &mut fragments[start..] {
+            if let JsonFragment::Literal(ref mut l) = *f {
+                *l = extended_json.translate(l);
+            }
+        }
     }
 
-    // Parse a repl
-    let (remainder, r) = repl(remainder);
-    if r.len() > 0 {
-        fragments.push(JsonFragment::Repl(r));
+    if opts.rust_values {
+        let translated = translate_fragments(fragments.drain(start..).collect());
+        fragments.extend(translated);
     }
 
-    // If there's anything left, run again
-    if remainder.len() > 0 {
-        parse_fragments(remainder, fragments);
+    if opts.expand_key_paths {
+        let expanded = expand_fragments(fragments.drain(start..).collect());
+        fragments.extend(expanded);
     }
 }
 
-// Parse a replacement ident.
-fn repl(remainder: &[u8]) -> (&[u8], &str) {
-    if remainder.len() == 0 {
-        return (&[], "");
+/// Find the first key that's used more than once in the same object in a sequence of fragments.
+///
+/// Replacements in key position are treated as keys named after the replacement, like `$field`,
+/// so the same replacement can't be used as a key twice in one object.
+/// Keys from other replacements and partials can't be known until they have values, so they're ignored.
+pub fn duplicate_key(fragments: &[JsonFragment]) -> Option<DuplicateKey> {
+    let mut json = String::new();
+    for f in fragments {
+        match *f {
+            JsonFragment::Literal(ref l) => json.push_str(l),
+            JsonFragment::KeyRepl(r) => {
+                let _ = write!(json, "\"{}{}\"", MARKER, r);
+            },
+            JsonFragment::StrRepl(_) => (),
+            _ => json.push_str("null")
+        }
     }
 
-    take_while(&remainder, (), |_, c| {
-        ((), is_ident(c as char))
-    })
+    let value = Value::parse(&json).ok()?;
+    let duplicate = value.duplicate_keys().into_iter().next()?;
+
+    //Show replacement keys the way they were written
+    let path = duplicate.path().replace(MARKER, "$");
+    let key = duplicate.key().replace(MARKER, "$");
+
+    Some(DuplicateKey::new(path, key))
 }
 
-// Parse a literal and maybe break on a replacement token.
-fn literal<'a>(remainder: &'a [u8], sanitised: &mut String, break_on_repl: bool) -> &'a [u8] {
-    if remainder.len() == 0 {
-        return &[];
+// A private use char that marks where replacements go while fragments are expanded.
+const MARKER: char = '\u{f8ff}';
+
+// Translate the Rust values in a sequence of fragments.
+// Replacements are swapped for markers so the whole template is translated at once,
+// then the translated json is split back into fragments at the markers.
+// A replacement in a repeat array, like `[$x; 2]`, is repeated along with the rest of the array.
+fn translate_fragments(fragments: Vec<JsonFragment<'_>>) -> Vec<JsonFragment<'_>> {
+    if fragments.iter().any(|f| matches!(*f, JsonFragment::Literal(ref l) if l.contains(MARKER))) {
+        return fragments;
+    }
+
+    let mut json = String::new();
+    for (i, f) in fragments.iter().enumerate() {
+        match *f {
+            JsonFragment::Literal(ref l) => json.push_str(l),
+            _ => {
+                let _ = write!(json, "{}{}{}", MARKER, i, MARKER);
+            }
+        }
     }
 
-    let current = remainder[0];
+    let json = RustValues::default().translate(&json);
 
-    match current {
-        //Key
-        b'"'|b'\'' => {
-            enum StringState {
-                Unescaped,
-                Escaped
+    let mut translated = Vec::new();
+
+    let mut parts = json.split(MARKER);
+    let mut literal = parts.next().unwrap_or("").to_owned();
+
+    while let (Some(i), Some(rest)) = (parts.next(), parts.next()) {
+        if !literal.is_empty() {
+            translated.push(JsonFragment::Literal(literal));
+        }
+        translated.extend(i.parse().ok().and_then(|i: usize| fragments.get(i)).cloned());
+
+        literal = rest.to_owned();
+    }
+
+    if !literal.is_empty() {
+        translated.push(JsonFragment::Literal(literal));
+    }
+
+    translated
+}
+
+// Expand the key paths in a sequence of fragments.
+// Replacements are swapped for marker strings so the whole template can be expanded as json,
+// then the expanded json is split back into fragments at the markers.
+fn expand_fragments(fragments: Vec<JsonFragment<'_>>) -> Vec<JsonFragment<'_>> {
+    if fragments.iter().any(|f| matches!(*f, JsonFragment::Literal(ref l) if l.contains(MARKER))) {
+        return fragments;
+    }
+
+    let mut json = String::new();
+    for (i, f) in fragments.iter().enumerate() {
+        match *f {
+            JsonFragment::Literal(ref l) => json.push_str(l),
+            JsonFragment::StrRepl(_) => {
+                let _ = write!(json, "{}{}{}", MARKER, i, MARKER);
+            },
+            _ => {
+                let _ = write!(json, "\"{}{}{}\"", MARKER, i, MARKER);
             }
+        }
+    }
+
+    let value = match Value::parse(&json) {
+        Ok(value) => value.expand_key_paths(),
+        Err(_) => return fragments
+    };
+
+    json.clear();
+    value.write(&mut json);
+
+    let mut fragments: Vec<Option<JsonFragment>> = fragments.into_iter().map(Some).collect();
+    let mut expanded = Vec::new();
 
-            let (rest, key) = take_while(&remainder[1..], StringState::Unescaped, 
-                |s, c| {
-                    match (s, c) {
-                        //Escape char
-                        (StringState::Unescaped, b'\\') => {
-                            (StringState::Escaped, true)
+    let mut parts = json.split(MARKER);
+    let mut literal = parts.next().unwrap_or("").to_owned();
+
+    while let (Some(i), Some(rest)) = (parts.next(), parts.next()) {
+        let f = i.parse().ok().and_then(|i: usize| fragments.get_mut(i)).and_then(Option::take);
+
+        //Markers for anything but string interpolations were quoted
+        let rest = match f {
+            Some(JsonFragment::StrRepl(_)) | None => rest,
+            Some(_) => {
+                literal.pop();
+                &rest[1..]
+            }
+        };
+
+        if !literal.is_empty() {
+            expanded.push(JsonFragment::Literal(literal));
+        }
+        expanded.extend(f);
+
+        literal = rest.to_owned();
+    }
+
+    if !literal.is_empty() {
+        expanded.push(JsonFragment::Literal(literal));
+    }
+
+    expanded
+}
+
+// Parse fragments, maybe starting in the middle of a string.
+fn parse_fragments_in<'a>(remainder: &'a [u8], open: Option<u8>, fragments: &mut Vec<JsonFragment<'a>>, opts: &ParseOptions) {
+    let mut remainder = remainder;
+    let mut open = open;
+
+    loop {
+        // Parse a literal, or the rest of a string we're in the middle of
+        let mut l = String::new();
+        let (rest, still_open) = match open {
+            Some(quote) => string(remainder, quote, &mut l, opts, true),
+            None => literal(remainder, &mut l, opts, true)
+        };
+        remainder = rest;
+        open = still_open;
+
+        if !l.is_empty() {
+            fragments.push(JsonFragment::Literal(l));
+        }
+
+        // Parse a repl, partial or interpolated string repl
+        remainder = match (open, remainder.first()) {
+            (Some(_), _) => {
+                match interpolation(&remainder[1..]) {
+                    Some((remainder, r)) => {
+                        fragments.push(JsonFragment::StrRepl(r));
+
+                        remainder
+                    },
+                    None => remainder
+                }
+            },
+            (None, Some(&c)) if c == opts.sigil() => {
+                //Strip trailing whitespace
+                let remainder = shift_while(&remainder[1..], |c| c == b' ');
+
+                let (remainder, r) = repl(remainder);
+                if !r.is_empty() {
+                    //Check if the replacement is a key
+                    match shift_while(remainder, is_whitespace).first() {
+                        Some(&b':') => fragments.push(JsonFragment::KeyRepl(r)),
+                        _ => fragments.push(JsonFragment::Repl(r))
+                    }
+                }
+
+                remainder
+            },
+            (None, Some(&b'{')) => {
+                match interpolation(remainder) {
+                    Some((remainder, c)) => {
+                        fragments.push(JsonFragment::Const(c));
+
+                        remainder
+                    },
+                    None => remainder
+                }
+            },
+            (None, Some(&b'@')) => {
+                match partial(&remainder[1..]) {
+                    Some((remainder, name, args)) => {
+                        fragments.push(JsonFragment::Partial(name, args));
+
+                        remainder
+                    },
+                    None => remainder
+                }
+            },
+            _ => remainder
+        };
+
+        // If there's anything left, run again
+        if remainder.is_empty() {
+            break;
+        }
+    }
+}
+
+// The state of translating Rust values in sanitised json.
+#[derive(Default)]
+struct RustValues {
+    in_string: bool,
+    open: Vec<Open>
+}
+
+// An open object, array, tuple or option.
+// Arrays keep their start so repeat arrays can be expanded.
+enum Open {
+    Object,
+    Array(usize),
+    Tuple,
+    Some
+}
+
+impl RustValues {
+    fn translate(&mut self, json: &str) -> String {
+        let mut translated = String::with_capacity(json.len());
+        let mut i = 0;
+
+        while let Some(c) = json[i..].chars().next() {
+            let rest = &json[i..];
+            i += c.len_utf8();
+
+            if self.in_string {
+                match c {
+                    '\\' => {
+                        translated.push(c);
+
+                        if let Some(escaped) = json[i..].chars().next() {
+                            translated.push(escaped);
+                            i += escaped.len_utf8();
+                        }
+
+                        continue;
+                    },
+                    '"' => self.in_string = false,
+                    _ => ()
+                }
+
+                translated.push(c);
+                continue;
+            }
+
+            match c {
+                '"' => {
+                    self.in_string = true;
+                    translated.push(c);
+                },
+                '{' => {
+                    self.open.push(Open::Object);
+                    translated.push(c);
+                },
+                '[' => {
+                    self.open.push(Open::Array(translated.len()));
+                    translated.push(c);
+                },
+                //Tuples become arrays
+                '(' => {
+                    self.open.push(Open::Tuple);
+                    translated.push('[');
+                },
+                //Options become their value
+                'S' if rest.starts_with("Some(") => {
+                    self.open.push(Open::Some);
+                    i += 4;
+                },
+                '}'|']'|')' => {
+                    match self.open.pop() {
+                        //Single element tuples have a trailing comma, like `(1,)`
+                        Some(Open::Tuple) => {
+                            if translated.ends_with(',') {
+                                translated.pop();
+                            }
+
+                            translated.push(']');
                         },
-                        //Ignore char after escape
-                        (StringState::Escaped, _) => {
-                            (StringState::Unescaped, true)
+                        Some(Open::Some) => (),
+                        _ => translated.push(c)
+                    }
+                },
+                //Repeat arrays, like `[0;4]`, are expanded
+                ';' => {
+                    let (after, digits) = take_while(&rest.as_bytes()[1..], (), |_, c| ((), c.is_ascii_digit()));
+
+                    match (self.open.last(), digits.parse::<usize>(), after.first()) {
+                        (Some(&Open::Array(start)), Ok(count), Some(&b']')) => {
+                            let value = translated.split_off(start + 1);
+
+                            for n in 0..count {
+                                if n > 0 {
+                                    translated.push(',');
+                                }
+
+                                translated.push_str(&value);
+                            }
+
+                            i += digits.len();
                         },
-                        //Unescaped quote
-                        (StringState::Unescaped, c) if c == current => {
-                            (StringState::Unescaped, false)
+                        _ => translated.push(c)
+                    }
+                },
+                //Integer ranges, like `1..=3`, become arrays
+                '-'|'0'..='9' => {
+                    let (after, number) = take_while(rest.as_bytes(), (), |_, c| {
+                        ((), c.is_ascii_digit() || c == b'.' || c == b'+' || c == b'-' || c == b'=' || c == b'e' || c == b'E')
+                    });
+
+                    match range(number) {
+                        Some(range) => {
+                            translated.push('[');
+
+                            for (n, v) in range.enumerate() {
+                                if n > 0 {
+                                    translated.push(',');
+                                }
+
+                                translated.push_str(&v.to_string());
+                            }
+
+                            translated.push(']');
                         },
-                        //Anything else
-                        _ => {
-                            (StringState::Unescaped, true)
-                        }
+                        None => translated.push_str(number)
                     }
+
+                    i = json.len() - after.len();
+                },
+                _ => translated.push(c)
+            }
+        }
+
+        translated
+    }
+}
+
+// The state of translating MongoDB shell calls in sanitised json into Extended JSON.
+// This carries over between literal fragments, so calls can take a replacement, like `ObjectId($id)`.
+#[derive(Default)]
+struct ExtendedJson {
+    in_string: bool,
+    open: usize
+}
+
+impl ExtendedJson {
+    fn translate(&mut self, json: &str) -> String {
+        let mut translated = String::with_capacity(json.len());
+        let mut i = 0;
+
+        while let Some(c) = json[i..].chars().next() {
+            let rest = &json[i..];
+            i += c.len_utf8();
+
+            if self.in_string {
+                match c {
+                    '\\' => {
+                        translated.push(c);
+
+                        if let Some(escaped) = json[i..].chars().next() {
+                            translated.push(escaped);
+                            i += escaped.len_utf8();
+                        }
+
+                        continue;
+                    },
+                    '"' => self.in_string = false,
+                    _ => ()
                 }
-            );
 
-            sanitised.push('"');
-            sanitised.push_str(key);
-            sanitised.push('"');
+                translated.push(c);
+                continue;
+            }
 
-            literal(&rest[1..], sanitised, break_on_repl)
-        },
-        //Start of item
-        b'{'|b'['|b':' => {
-            sanitised.push(current as char);
+            match c {
+                '"' => {
+                    self.in_string = true;
+                    translated.push(c);
+                },
+                //Calls are the only idents left unquoted by the sanitiser
+                'A'..='Z' if extended_json_call_start(rest).is_some() => {
+                    let (after, name, key) = extended_json_call_start(rest).unwrap_or((&[], "", ""));
+
+                    match call_args(after) {
+                        //The whole call is in this fragment
+                        Some((after, args)) => {
+                            match extended_json_call(name, args) {
+                                Ok(value) => value.write(&mut translated),
+                                Err(reason) => panic!("invalid MongoDB shell call '{}({})': {}", name, args, reason)
+                            }
+
+                            i = json.len() - after.len();
+                        },
+                        //The call takes a replacement, so it's closed in a later fragment
+                        None => {
+                            let _ = write!(translated, "{{\"{}\":", key);
+
+                            self.open += 1;
+                            i = json.len() - after.len();
+                        }
+                    }
+                },
+                ')' if self.open > 0 => {
+                    self.open -= 1;
+                    translated.push('}');
+                },
+                _ => translated.push(c)
+            }
+        }
+
+        translated
+    }
+}
+
+// Get the name and Extended JSON key of a MongoDB shell call, with the input after its opening paren.
+fn extended_json_call_start(json: &str) -> Option<(&[u8], &str, &'static str)> {
+    let (after, name) = take_while(json.as_bytes(), (), |_, c| ((), is_ident(c)));
+    let key = extended_json_key(name)?;
+
+    match after.first() {
+        Some(&b'(') => Some((&after[1..], name, key)),
+        _ => None
+    }
+}
+
+// Get the Extended JSON key for a MongoDB shell call, like `$oid` for `ObjectId`.
+fn extended_json_key(name: &str) -> Option<&'static str> {
+    match name {
+        "ObjectId" => Some("$oid"),
+        "ISODate" => Some("$date"),
+        "NumberInt" => Some("$numberInt"),
+        "NumberLong" => Some("$numberLong"),
+        "NumberDecimal" => Some("$numberDecimal"),
+        "Timestamp" => Some("$timestamp"),
+        "MinKey" => Some("$minKey"),
+        "MaxKey" => Some("$maxKey"),
+        _ => None
+    }
+}
+
+// Get the arguments of a call, up to its closing paren, if it's closed before the end of the input.
+fn call_args(remainder: &[u8]) -> Option<(&[u8], &str)> {
+    let mut in_string = false;
+    let mut i = 0;
+
+    while i < remainder.len() {
+        match remainder[i] {
+            b'\\' if in_string => i += 1,
+            b'"' => in_string = !in_string,
+            b')' if !in_string => {
+                let args = unsafe { str::from_utf8_unchecked(&remainder[..i]) };
+
+                return Some((&remainder[i + 1..], args));
+            },
+            _ => ()
+        }
 
-            literal(&remainder[1..], sanitised, break_on_repl)
+        i += 1;
+    }
+
+    None
+}
+
+// Translate a MongoDB shell call into its canonical Extended JSON.
+// Returns why the call is invalid if its arguments have the wrong type or number.
+fn extended_json_call(name: &str, args: &str) -> Result<Value, &'static str> {
+    let args = match Value::parse(&format!("[{}]", args)) {
+        Ok(Value::Array(args)) => args,
+        _ => return Err("its arguments aren't valid json")
+    };
+
+    let key = extended_json_key(name).ok_or("it isn't a MongoDB shell call")?.to_owned();
+
+    let number_long = |millis: String| Value::Object(vec![("$numberLong".to_owned(), Value::String(millis))]);
+
+    let value = match (name, &args[..]) {
+        ("ObjectId", [Value::String(ref id)]) if id.len() == 24 && id.bytes().all(|c| c.is_ascii_hexdigit()) => args[0].clone(),
+        ("ObjectId", _) => return Err("expected a string of 24 hex digits"),
+        ("ISODate", [Value::String(ref date)]) => match iso_date_millis(date) {
+            Some(millis) => number_long(millis.to_string()),
+            None => return Err("expected an ISO 8601 date, like \"2016-10-19T08:30:00Z\"")
         },
-        //Trim whitespace
-        b' '|b'\r'|b'\n'|b'\t' => {
-            literal(&remainder[1..], sanitised, break_on_repl)
+        ("ISODate", [Value::Number(ref millis)]) if millis.parse::<i64>().is_ok() => number_long(millis.clone()),
+        ("ISODate", _) => return Err("expected an ISO 8601 date string or milliseconds since the Unix epoch"),
+        ("NumberInt", [Value::Number(ref n)]) |
+        ("NumberInt", [Value::String(ref n)]) if n.parse::<i32>().is_ok() => Value::String(n.clone()),
+        ("NumberInt", _) => return Err("expected a 32-bit integer"),
+        ("NumberLong", [Value::Number(ref n)]) |
+        ("NumberLong", [Value::String(ref n)]) if n.parse::<i64>().is_ok() => Value::String(n.clone()),
+        ("NumberLong", _) => return Err("expected a 64-bit integer"),
+        ("NumberDecimal", [Value::Number(ref n)]) => Value::String(n.clone()),
+        ("NumberDecimal", [Value::String(ref n)]) if is_decimal(n) => Value::String(n.clone()),
+        ("NumberDecimal", _) => return Err("expected a decimal number"),
+        ("Timestamp", [Value::Number(ref t), Value::Number(ref i)]) if t.parse::<u32>().is_ok() && i.parse::<u32>().is_ok() => Value::Object(vec![
+            ("t".to_owned(), args[0].clone()),
+            ("i".to_owned(), args[1].clone())
+        ]),
+        ("Timestamp", _) => return Err("expected the seconds since the Unix epoch and an increment, as 32-bit unsigned integers"),
+        ("MinKey", []) | ("MaxKey", []) => Value::Number("1".to_owned()),
+        _ => return Err("expected no arguments")
+    };
+
+    Ok(Value::Object(vec![(key, value)]))
+}
+
+// Check whether a string is a decimal number, including the special values of a 128-bit decimal.
+fn is_decimal(n: &str) -> bool {
+    match n {
+        "NaN" | "Infinity" | "-Infinity" => true,
+        n => matches!(Value::parse(n), Ok(Value::Number(_)))
+    }
+}
+
+// Get the milliseconds since the Unix epoch for an ISO 8601 date, like `2016-10-19T08:30:00.5+10:00`.
+// Dates without an offset are in UTC.
+fn iso_date_millis(date: &str) -> Option<i64> {
+    fn digits(s: &str, n: usize) -> Option<(i64, &str)> {
+        if s.len() < n || !s.as_bytes()[..n].iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+
+        Some((s[..n].parse().ok()?, &s[n..]))
+    }
+
+    let (year, rest) = digits(date, 4)?;
+    let (month, rest) = digits(rest.strip_prefix('-')?, 2)?;
+    let (day, rest) = digits(rest.strip_prefix('-')?, 2)?;
+
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    };
+
+    if !(1..=12).contains(&month) || !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+
+    let (mut millis, rest) = match rest.strip_prefix(['T', ' ']) {
+        Some(time) => {
+            let (hour, rest) = digits(time, 2)?;
+            let (minute, rest) = digits(rest.strip_prefix(':')?, 2)?;
+            let (second, rest) = match rest.strip_prefix(':') {
+                Some(rest) => digits(rest, 2)?,
+                None => (0, rest)
+            };
+
+            //Only the first 3 digits of fractional seconds are kept
+            let (fraction, rest) = match rest.strip_prefix('.') {
+                Some(rest) => {
+                    let len = rest.bytes().take_while(u8::is_ascii_digit).count();
+                    let (fraction, _) = digits(&format!("{:0<3}", &rest[..len]), 3)?;
+
+                    (fraction, &rest[len..])
+                },
+                None => (0, rest)
+            };
+
+            if hour > 23 || minute > 59 || second > 59 {
+                return None;
+            }
+
+            (((hour * 60 + minute) * 60 + second) * 1000 + fraction, rest)
         },
-        //Unquoted key
-        b if (b as char).is_alphabetic() => {
-            let (rest, key) = take_while(remainder, (), |_, c| {
-                ((), is_ident(c as char))
-            });
+        None => (0, rest)
+    };
 
-            //Check if the string is a special value; true, false or null
-            //For special values, push them as straight unquoted values. Otherwise, quote them
-            match key {
-                "true"|"false"|"null" =>
-                    sanitised.push_str(key),
-                _ => {
+    match rest {
+        "" | "Z" => (),
+        _ => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None
+            };
+
+            let (hours, offset) = digits(&rest[1..], 2)?;
+            let (minutes, offset) = digits(offset.strip_prefix(':').unwrap_or(offset), 2)?;
+
+            if !offset.is_empty() || hours > 23 || minutes > 59 {
+                return None;
+            }
+
+            millis -= sign * (hours * 60 + minutes) * 60 * 1000;
+        }
+    }
+
+    //Days since the epoch for a date in the proleptic Gregorian calendar
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    Some(days * 86_400_000 + millis)
+}
+
+// Parse an integer range, like `1..4` or `1..=3`.
+fn range(number: &str) -> Option<impl Iterator<Item = i64>> {
+    let mut bounds = number.splitn(2, "..");
+    let start = bounds.next()?.parse::<i64>().ok()?;
+    let end = bounds.next()?;
+
+    let end = match end.strip_prefix('=') {
+        Some(end) => end.parse::<i64>().ok()?,
+        None => end.parse::<i64>().ok()?.checked_sub(1)?
+    };
+
+    Some(start..=end)
+}
+
+/// Escape a value so it can be pushed as the contents of a json string.
+///
+/// The value isn't wrapped in quotes.
+pub fn escape(value: &str, json: &mut String) {
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            '\u{8}' => json.push_str("\\b"),
+            '\u{c}' => json.push_str("\\f"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c)
+        }
+    }
+}
+
+// Parse a replacement ident.
+fn repl(remainder: &[u8]) -> (&[u8], &str) {
+    if remainder.is_empty() {
+        return (&[], "");
+    }
+
+    take_while(remainder, (), |_, c| {
+        ((), is_ident(c))
+    })
+}
+
+// Parse a partial call, like `match_clause(field, value)`.
+// Partial names can also be paths, like `queries/match_clause(field, value)`.
+fn partial(remainder: &[u8]) -> Option<(&[u8], &str, Vec<&str>)> {
+    let remainder = shift_while(remainder, is_whitespace);
+    let (remainder, name) = take_while(remainder, (), |_, c| {
+        ((), is_ident(c) || c == b'/')
+    });
+
+    let remainder = shift_while(remainder, is_whitespace);
+    if name.is_empty() || remainder.first() != Some(&b'(') {
+        return None;
+    }
+
+    let mut args = Vec::new();
+    let mut remainder = &remainder[1..];
+
+    loop {
+        remainder = shift_while(remainder, |c| is_whitespace(c) || c == b',');
+
+        match remainder.first() {
+            Some(&b')') => return Some((&remainder[1..], name, args)),
+            Some(_) => {
+                let (rest, arg) = repl(remainder);
+                if arg.is_empty() {
+                    return None;
+                }
+
+                args.push(arg);
+                remainder = rest;
+            },
+            None => return None
+        }
+    }
+}
+
+// Parse the contents of an attribute after its `#`, like `[cfg(debug_assertions)]`.
+fn attribute(remainder: &[u8]) -> Option<(&[u8], &str)> {
+    let remainder = shift_while(remainder, is_whitespace);
+    if remainder.first() != Some(&b'[') {
+        return None;
+    }
+
+    let mut depth = 0;
+    let mut quoted = false;
+
+    for (i, &c) in remainder.iter().enumerate().skip(1) {
+        match c {
+            b'"' => quoted = !quoted,
+            b'[' if !quoted => depth += 1,
+            b']' if !quoted && depth == 0 => {
+                return Some((&remainder[i + 1..], unsafe { str::from_utf8_unchecked(&remainder[1..i]) }));
+            },
+            b']' if !quoted => depth -= 1,
+            _ => ()
+        }
+    }
+
+    None
+}
+
+// Get the predicate of a `cfg` attribute, like `debug_assertions` in `cfg(debug_assertions)`.
+fn cfg_predicate(attr: &str) -> Option<&str> {
+    attr.trim()
+        .strip_prefix("cfg")?
+        .trim_start()
+        .strip_prefix('(')?
+        .strip_suffix(')')
+}
+
+// Skip a member or element, up to the next `,` or the end of the object or array it's in.
+fn skip_item(remainder: &[u8]) -> &[u8] {
+    let mut depth = 0;
+    let mut i = 0;
+
+    while i < remainder.len() {
+        match remainder[i] {
+            //Skip over strings, which might contain commas or brackets
+            quote @ b'"' | quote @ b'\'' if quote == b'"' || !is_lifetime(&remainder[i + 1..]) => {
+                i += 1;
+
+                while i < remainder.len() && remainder[i] != quote {
+                    if remainder[i] == b'\\' {
+                        i += 1;
+                    }
+
+                    i += 1;
+                }
+            },
+            b'{'|b'['|b'(' => depth += 1,
+            b'}'|b']'|b')' if depth == 0 => return &remainder[i..],
+            b'}'|b']'|b')' => depth -= 1,
+            b',' if depth == 0 => return &remainder[i + 1..],
+            _ => ()
+        }
+
+        i += 1;
+    }
+
+    &[]
+}
+
+// Parse a MongoDB operator key after its `$`, like `gt` in `$gt: 1`.
+// Any key that isn't one of the given replacements is an operator.
+// If the replacements aren't known then it has to be a well-known operator.
+fn operator_key<'a>(remainder: &'a [u8], replacements: Option<&[String]>) -> Option<(&'a [u8], &'a str)> {
+    const OPERATORS: &[&str] = &[
+        //Query
+        "eq", "gt", "gte", "in", "lt", "lte", "ne", "nin", "and", "not", "nor", "or", "exists", "type",
+        "expr", "jsonSchema", "mod", "regex", "options", "text", "search", "language", "where",
+        "geoIntersects", "geoWithin", "near", "nearSphere", "geometry", "maxDistance", "minDistance",
+        "box", "center", "centerSphere", "polygon", "all", "elemMatch", "size",
+        "bitsAllClear", "bitsAllSet", "bitsAnyClear", "bitsAnySet", "meta", "slice", "comment", "rand",
+        //Update
+        "currentDate", "inc", "min", "max", "mul", "rename", "set", "setOnInsert", "unset",
+        "addToSet", "pop", "pull", "push", "pullAll", "each", "position", "sort", "bit",
+        //Aggregation
+        "match", "group", "project", "addFields", "lookup", "unwind", "limit", "skip", "count",
+        "facet", "bucket", "bucketAuto", "sortByCount", "replaceRoot", "replaceWith", "unionWith",
+        "out", "merge", "sample", "graphLookup", "redact", "sum", "avg", "first", "last",
+        "cond", "ifNull", "concat", "toString", "dateToString", "arrayElemAt", "filter", "map",
+        "reduce", "let", "switch", "literal"
+    ];
+
+    let (rest, key) = take_while(remainder, (), |_, c| {
+        ((), is_ident(c))
+    });
+
+    let is_operator = match replacements {
+        Some(replacements) => !replacements.iter().any(|r| r == key),
+        None => OPERATORS.contains(&key)
+    };
+
+    if !key.is_empty() && is_operator && shift_while(rest, is_whitespace).first() == Some(&b':') {
+        Some((rest, key))
+    }
+    else {
+        None
+    }
+}
+
+// Parse an interpolated replacement ident or a constant, like `{date}`.
+fn interpolation(remainder: &[u8]) -> Option<(&[u8], &str)> {
+    if remainder.first() != Some(&b'{') {
+        return None;
+    }
+
+    let remainder = shift_while(&remainder[1..], is_whitespace);
+    let (remainder, r) = repl(remainder);
+
+    let remainder = shift_while(remainder, is_whitespace);
+    if r.is_empty() || remainder.first() != Some(&b'}') {
+        return None;
+    }
+
+    Some((&remainder[1..], r))
+}
+
+// Parse a literal and maybe break on a replacement token.
+// If we break in the middle of a string then its opening quote is also returned.
+fn literal<'a>(remainder: &'a [u8], sanitised: &mut String, opts: &ParseOptions, break_on_repl: bool) -> (&'a [u8], Option<u8>) {
+    let mut remainder = remainder;
+
+    //Each char is handled in a loop rather than by recursing, so large inputs don't overflow the stack
+    while let Some(&current) = remainder.first() {
+        remainder = match current {
+            //Attribute, like `#[cfg(debug_assertions)]`, which is removed
+            //If it's a `cfg` that doesn't match then the member or element it's on is removed too
+            b'#' if attribute(&remainder[1..]).is_some() => {
+                let (rest, attr) = attribute(&remainder[1..]).unwrap_or((&[], ""));
+
+                match cfg_predicate(attr).map(|predicate| opts.cfg.matches(predicate)) {
+                    Some(Some(false)) => {
+                        let rest = skip_item(rest);
+
+                        //Don't leave a trailing comma behind if this was the last item
+                        if let Some(&b'}') | Some(&b']') = shift_while(rest, is_whitespace).first() {
+                            if sanitised.ends_with(',') {
+                                sanitised.pop();
+                            }
+                        }
+
+                        rest
+                    },
+                    //Malformed predicates are kept so the result is invalid
+                    Some(None) => {
+                        sanitised.push_str(unsafe { str::from_utf8_unchecked(&remainder[..remainder.len() - rest.len()]) });
+
+                        rest
+                    },
+                    _ => rest
+                }
+            },
+            //Lifetime, like `'static`, which is treated like an unquoted key
+            b'\'' if is_lifetime(&remainder[1..]) => {
+                let (rest, key) = take_while(&remainder[1..], (), |_, c| {
+                    ((), is_ident(c))
+                });
+
+                sanitised.push_str("\"'");
+                sanitised.push_str(key);
+                sanitised.push('"');
+
+                rest
+            },
+            //Key, or a char literal like `'a'`
+            b'"'|b'\'' => {
+                sanitised.push('"');
+
+                match string_contents(&remainder[1..], current, sanitised, opts, break_on_repl) {
+                    (rest, None) => rest,
+                    broken => return broken
+                }
+            },
+            //Byte char literal, which is treated like any other char literal
+            b'b' if remainder.get(1) == Some(&b'\'') => {
+                &remainder[1..]
+            },
+            //Raw string
+            b'r' if raw_string(&remainder[1..]).is_some() => {
+                let (rest, raw) = raw_string(&remainder[1..]).unwrap_or((&[], ""));
+
+                //Raw strings don't have escapes, so quotes and backslashes need to be escaped
+                sanitised.push('"');
+                for c in raw.chars() {
+                    match c {
+                        '"'|'\\' => escape(&c.to_string(), sanitised),
+                        c if (c as u32) < 0x20 && opts.escape_control_chars => escape(&c.to_string(), sanitised),
+                        c => sanitised.push(c)
+                    }
+                }
+                sanitised.push('"');
+
+                rest
+            },
+            //Byte string, which is treated like any other string
+            b'b' if remainder.get(1) == Some(&b'"') => {
+                &remainder[1..]
+            },
+            //Raw byte string
+            b'b' if remainder.get(1) == Some(&b'r') && raw_string(&remainder[2..]).is_some() => {
+                &remainder[1..]
+            },
+            //Constant
+            b'{' if break_on_repl && interpolation(remainder).is_some() => {
+                return (remainder, None);
+            },
+            //Start of item
+            b'{'|b'['|b':' => {
+                sanitised.push(current as char);
+
+                &remainder[1..]
+            },
+            //Trim whitespace
+            b' '|b'\r'|b'\n'|b'\t' => {
+                &remainder[1..]
+            },
+            //Raw identifier, like `r#type`, which is quoted without the `r#`
+            b'r' if remainder.get(1) == Some(&b'#') && remainder.get(2).is_some_and(|&c| is_ident_start(c)) => {
+                let (rest, key) = take_while(&remainder[2..], (), |_, c| {
+                    ((), is_key(c))
+                });
+
+                sanitised.push('"');
+                sanitised.push_str(key);
+                sanitised.push('"');
+
+                rest
+            },
+            //MongoDB operator key, like `$gt`, which is quoted instead of being a replacement
+            //Literals can't have replacements, so any key is an operator there
+            b'$' if opts.extended_json && operator_key(&remainder[1..], opts.key_replacements(break_on_repl)).is_some() => {
+                let (rest, key) = operator_key(&remainder[1..], opts.key_replacements(break_on_repl)).unwrap_or((&[], ""));
+
+                sanitised.push_str("\"$");
+                sanitised.push_str(key);
+                sanitised.push('"');
+
+                rest
+            },
+            //Escaped sigil, like `$$gt`, which is quoted with a single sigil instead of being a replacement
+            b if b == opts.sigil() && remainder.get(1) == Some(&b) && remainder.get(2).is_some_and(|&c| is_ident_start(c)) => {
+                let (rest, key) = take_while(&remainder[2..], (), |_, c| {
+                    ((), is_key(c))
+                });
+
+                sanitised.push('"');
+                sanitised.push(b as char);
+                sanitised.push_str(key);
+                sanitised.push('"');
+
+                rest
+            },
+            //Replacement
+            b if b == opts.sigil() && break_on_repl => {
+                return (remainder, None);
+            },
+            //Partial
+            b'@' if break_on_repl && partial(&remainder[1..]).is_some() => {
+                return (remainder, None);
+            },
+            //Unquoted key, which can also contain digits, `-` and `.`, and start with `@`,
+            //or with `$` if it isn't the sigil
+            b if is_ident_start(b) || (is_key_prefix(b, opts) && remainder.get(1).is_some_and(|&c| is_ident_start(c))) => {
+                let (rest, key) = take_while(&remainder[1..], (), |_, c| {
+                    ((), is_key(c))
+                });
+                let key = unsafe { str::from_utf8_unchecked(&remainder[..key.len() + 1]) };
+
+                //Check if the string is a special value; true, false or null
+                //For special values, push them as straight unquoted values. Otherwise, quote them
+                let next = shift_while(rest, is_whitespace).first();
+
+                match key {
+                    "true"|"false"|"null" =>
+                        sanitised.push_str(key),
+                    //Rust options are translated later, once we know where they end
+                    "None" if opts.rust_values && next != Some(&b':') =>
+                        sanitised.push_str("null"),
+                    "Some" if opts.rust_values && next == Some(&b'(') =>
+                        sanitised.push_str(key),
+                    //MongoDB shell calls are translated later, once we know where they end
+                    key if opts.extended_json && next == Some(&b'(') && extended_json_key(key).is_some() =>
+                        sanitised.push_str(key),
+                    _ => {
+                        sanitised.push('"');
+                        sanitised.push_str(key);
+                        sanitised.push('"');
+                    }
+                }
+
+                rest
+            },
+            //Number, which is quoted if it's in key position
+            b if b.is_ascii_digit() => {
+                let (rest, key) = take_while(remainder, (), |_, c| {
+                    ((), {
+                        c.is_ascii_digit() ||
+                        c == b'.' ||
+                        c == b'+' ||
+                        c == b'-' ||
+                        c == b'e' ||
+                        c == b'E'
+                    })
+                });
+
+                if shift_while(rest, is_whitespace).first() == Some(&b':') {
                     sanitised.push('"');
                     sanitised.push_str(key);
                     sanitised.push('"');
                 }
+                else {
+                    sanitised.push_str(key);
+                }
+
+                rest
+            },
+            //Other chars
+            _ => {
+                sanitised.push(current as char);
+
+                &remainder[1..]
+            }
+        };
+    }
+
+    (&[], None)
+}
+
+// Parse the rest of a string after its opening quote and maybe break on an interpolated replacement,
+// then carry on with the literal after it.
+fn string<'a>(remainder: &'a [u8], quote: u8, sanitised: &mut String, opts: &ParseOptions, break_on_repl: bool) -> (&'a [u8], Option<u8>) {
+    match string_contents(remainder, quote, sanitised, opts, break_on_repl) {
+        (rest, None) => literal(rest, sanitised, opts, break_on_repl),
+        broken => broken
+    }
+}
+
+// Parse the rest of a string after its opening quote, up to and including its closing quote.
+// If we break on an interpolated replacement then the string's opening quote is also returned.
+fn string_contents<'a>(remainder: &'a [u8], quote: u8, sanitised: &mut String, opts: &ParseOptions, break_on_repl: bool) -> (&'a [u8], Option<u8>) {
+    let mut remainder = remainder;
+
+    loop {
+        let (rest, key) = take_while(remainder, (), |_, c| {
+            ((), {
+                c != quote &&
+                c != b'"' &&
+                c != b'\\' &&
+                c != opts.sigil() &&
+                !(opts.escape_control_chars && c < 0x20)
+            })
+        });
+
+        sanitised.push_str(key);
+
+        match rest.first() {
+            //Control char
+            Some(&c) if c < 0x20 && opts.escape_control_chars => {
+                escape(&(c as char).to_string(), sanitised);
+                remainder = &rest[1..];
+            },
+            //Double quote in a single quoted string
+            Some(&b'"') if quote != b'"' => {
+                sanitised.push_str("\\\"");
+                remainder = &rest[1..];
+            },
+            //Escape char
+            Some(&b'\\') => {
+                remainder = string_escape(&rest[1..], sanitised);
+            },
+            //Escaped interpolation, like `$${date}`, which is kept with a single sigil
+            Some(&c) if c == opts.sigil() && rest.get(1) == Some(&c) && interpolation(&rest[2..]).is_some() => {
+                sanitised.push(c as char);
+                remainder = &rest[2..];
+            },
+            //Interpolated replacement
+            Some(&c) if c == opts.sigil() && break_on_repl && interpolation(&rest[1..]).is_some() => {
+                return (rest, Some(quote));
+            },
+            //Just a sigil
+            Some(&c) if c == opts.sigil() => {
+                sanitised.push(c as char);
+                remainder = &rest[1..];
+            },
+            //End of string
+            Some(_) => {
+                sanitised.push('"');
+
+                return (&rest[1..], None);
+            },
+            //Unterminated string
+            None => {
+                sanitised.push('"');
+
+                return (rest, None);
             }
+        }
+    }
+}
+
+// Parse an escape sequence after its `\`.
+// Json escapes are kept as-is, and Rust escapes are converted to their json equivalent.
+fn string_escape<'a>(remainder: &'a [u8], sanitised: &mut String) -> &'a [u8] {
+    let current = match remainder.first() {
+        Some(&c) => c,
+        None => {
+            sanitised.push('\\');
+            return remainder;
+        }
+    };
+
+    match current {
+        //Json escapes
+        b'"'|b'\\'|b'/'|b'b'|b'f'|b'n'|b'r'|b't' => {
+            sanitised.push('\\');
+            sanitised.push(current as char);
 
-            literal(rest, sanitised, break_on_repl)
+            &remainder[1..]
         },
-        //Number
-        b if (b as char).is_numeric() => {
-            let (rest, key) = take_while(remainder, (), |_, c| {
-                ((), {
-                   (c as char).is_numeric() || 
-                    c == b'.' || 
-                    c == b'+' || 
-                    c == b'-' || 
-                    c == b'e' || 
-                    c == b'E'
-                })
+        //Rust unicode escape, like `\u{1F600}`
+        b'u' if remainder.get(1) == Some(&b'{') => {
+            let (rest, hex) = take_while(&remainder[2..], (), |_, c| {
+                ((), c != b'}')
             });
 
-            sanitised.push_str(key);
+            let c = u32::from_str_radix(&hex.replace('_', ""), 16).ok().and_then(char::from_u32);
 
-            literal(rest, sanitised, break_on_repl)
+            match (c, rest.first()) {
+                (Some(c), Some(&b'}')) => {
+                    push_unicode_escape(c, sanitised);
+
+                    &rest[1..]
+                },
+                _ => {
+                    sanitised.push_str("\\u");
+
+                    &remainder[1..]
+                }
+            }
         },
-        //Replacement
-        b'$' if break_on_repl => {
-            //Strip trailing whitespace
-            let remainder = shift_while(&remainder[1..], |c| c == b' ');
+        //Json unicode escape, like `\u00e9`
+        b'u' => {
+            sanitised.push_str("\\u");
 
-            remainder
+            &remainder[1..]
+        },
+        //Rust null
+        b'0' => {
+            sanitised.push_str("\\u0000");
+
+            &remainder[1..]
+        },
+        //Rust single quote
+        b'\'' => {
+            sanitised.push('\'');
+
+            &remainder[1..]
+        },
+        //Rust byte escape, like `\x7f`
+        b'x' if remainder.len() >= 3 && remainder[1..3].iter().all(|c| c.is_ascii_hexdigit()) => {
+            let hex = unsafe { str::from_utf8_unchecked(&remainder[1..3]) };
+
+            sanitised.push_str("\\u00");
+            sanitised.push_str(&hex.to_lowercase());
+
+            &remainder[3..]
         },
-        //Other chars
+        //Rust line continuation, which skips the newline and any leading whitespace
+        b'\r'|b'\n' => {
+            shift_while(remainder, is_whitespace)
+        },
+        //Anything else isn't a valid escape, so leave it alone
         _ => {
-            sanitised.push(current as char);
+            sanitised.push('\\');
 
-            literal(&remainder[1..], sanitised, break_on_repl)
+            remainder
         }
     }
 }
 
+// Parse a raw string, like `r#"a "raw" string"#`, after its `r`.
+// Returns `None` if the input isn't a raw string.
+fn raw_string(remainder: &[u8]) -> Option<(&[u8], &str)> {
+    let (rest, hashes) = take_while(remainder, (), |_, c| ((), c == b'#'));
+
+    if rest.first() != Some(&b'"') {
+        return None;
+    }
+
+    //Find the closing quote followed by the same number of hashes
+    let rest = &rest[1..];
+    let end = (0..rest.len()).find(|&i| {
+        rest[i] == b'"' && rest[i + 1..].starts_with(hashes.as_bytes())
+    })?;
+
+    let raw = unsafe { str::from_utf8_unchecked(&rest[..end]) };
+
+    Some((&rest[end + 1 + hashes.len()..], raw))
+}
+
+// Push a char as a json unicode escape, using a surrogate pair if it's outside the basic multilingual plane.
+fn push_unicode_escape(c: char, sanitised: &mut String) {
+    let mut buf = [0; 2];
+
+    for unit in c.encode_utf16(&mut buf) {
+        sanitised.push_str(&format!("\\u{:04x}", unit));
+    }
+}
+
+// Check whether the input after a `'` is a lifetime rather than a single quoted string.
+// A lifetime is an ident that isn't closed by a quote and is followed by the end of a value.
+fn is_lifetime(remainder: &[u8]) -> bool {
+    let (rest, ident) = take_while(remainder, (), |_, c| {
+        ((), is_ident(c))
+    });
+
+    if ident.is_empty() || !is_value_end(rest) {
+        return false;
+    }
+
+    //If the next quote is followed by whitespace or the end of a value then it closes a string, like `'a,b'`
+    //Otherwise it's the start of something else, like the next key in `'a: 1, 'b: 2`
+    let mut i = 0;
+
+    while i < rest.len() {
+        match rest[i] {
+            b'\\' => i += 2,
+            b'\'' => {
+                let after = &rest[i + 1..];
+
+                return !(after.first().is_some_and(|&c| is_whitespace(c)) || is_value_end(after));
+            },
+            _ => i += 1
+        }
+    }
+
+    true
+}
+
+// Check whether the input is at the end of a value, ignoring any whitespace.
+fn is_value_end(remainder: &[u8]) -> bool {
+    matches!(shift_while(remainder, is_whitespace).first(), Some(&b',')|Some(&b'}')|Some(&b']')|Some(&b')')|Some(&b':')|None)
+}
+
+#[inline]
+fn is_ident_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_' || c >= 0x80
+}
+
+#[inline]
+fn is_ident(c: u8) -> bool {
+    is_ident_start(c) || c.is_ascii_digit()
+}
+
+#[inline]
+fn is_key(c: u8) -> bool {
+    is_ident(c) || c == b'-' || c == b'.'
+}
+
+#[inline]
+fn is_key_prefix(c: u8, opts: &ParseOptions) -> bool {
+    c == b'@' || (c == b'$' && opts.sigil() != b'$')
+}
+
 #[inline]
-fn is_ident(c: char) -> bool {
-    c.is_alphabetic() || c == '_'
+fn is_whitespace(c: u8) -> bool {
+    c == b' ' || c == b'\r' || c == b'\n' || c == b'\t'
 }
 
 fn shift_while<F>(i: &[u8], f: F) -> &[u8]
@@ -0,0 +1,40 @@
+/// A template that can be rendered as part of another template.
+///
+/// Any closure that takes some number of `&str` replacement values and returns a json string
+/// can be used as a partial. That includes closures built by `json_fn`.
+///
+/// The `Args` parameter is a tuple of the argument types the partial expects.
+/// It's only there to tell apart closures with different numbers of arguments.
+pub trait Partial<'a, Args> {
+    /// Render the partial with the given replacement values and push the result onto `json`.
+    ///
+    /// Panics if the number of replacement values doesn't match the number of arguments
+    /// the partial expects.
+    fn render(&self, args: &[&'a str], json: &mut String);
+}
+
+macro_rules! impl_partial {
+    ($len:expr, $($arg:ident: $idx:tt),*) => {
+        impl<'a, F, R> Partial<'a, ($(impl_partial!(@ty $arg),)*)> for F
+            where F: Fn($(impl_partial!(@ty $arg)),*) -> R,
+                  R: AsRef<str>
+        {
+            fn render(&self, args: &[&'a str], json: &mut String) {
+                assert!(args.len() == $len, "partial expects {} args but was given {}", $len, args.len());
+
+                json.push_str(self($(args[$idx]),*).as_ref());
+            }
+        }
+    };
+    (@ty $arg:ident) => (&'a str)
+}
+
+impl_partial!(0, );
+impl_partial!(1, a: 0);
+impl_partial!(2, a: 0, b: 1);
+impl_partial!(3, a: 0, b: 1, c: 2);
+impl_partial!(4, a: 0, b: 1, c: 2, d: 3);
+impl_partial!(5, a: 0, b: 1, c: 2, d: 3, e: 4);
+impl_partial!(6, a: 0, b: 1, c: 2, d: 3, e: 4, f: 5);
+impl_partial!(7, a: 0, b: 1, c: 2, d: 3, e: 4, f: 5, g: 6);
+impl_partial!(8, a: 0, b: 1, c: 2, d: 3, e: 4, f: 5, g: 6, h: 7);
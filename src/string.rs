@@ -0,0 +1,267 @@
+use std::borrow::{Borrow, ToOwned};
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+use value::{CanonicalError, DuplicateKey, ParseError, Value};
+
+/// A borrowed string that's valid json.
+///
+/// `JsonStr` is to `JsonString` what `str` is to `String`.
+/// It dereferences to a `str`, so it can be used anywhere a `&str` can.
+///
+/// Json is validated a bit more strictly than RFC 8259's grammar requires.
+/// Strings must be valid Unicode, so escapes of lone surrogates, like `"\ud83d"`, are rejected,
+/// as they are by I-JSON from RFC 7493.
+/// That means the strings in a `JsonStr` can always be decoded into Rust strings.
+///
+/// # Examples
+///
+/// ```
+/// use json_str::JsonStr;
+///
+/// let json = JsonStr::from_str("{\"a\":1}").unwrap();
+///
+/// assert!(JsonStr::from_str("{a:1}").is_err());
+/// ```
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct JsonStr(str);
+
+impl JsonStr {
+    /// Validate a string as json.
+    ///
+    /// Arrays and objects nested more than 128 deep are an error, like in `Value::parse`.
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(json: &str) -> Result<&JsonStr, ParseError> {
+        Value::parse(json)?;
+
+        Ok(JsonStr::from_str_unchecked(json))
+    }
+
+    /// Use a string as json without validating it.
+    ///
+    /// The caller is responsible for making sure the string is valid json.
+    pub fn from_str_unchecked(json: &str) -> &JsonStr {
+        unsafe { &*(json as *const str as *const JsonStr) }
+    }
+
+    /// Get the json as a `str`.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Get the canonical form of the json, per RFC 8785.
+    ///
+    /// Json that only differs in key order, number formatting or escaping has the same canonical form,
+    /// so it can be hashed or signed.
+    pub fn to_canonical(&self) -> Result<JsonString, CanonicalError> {
+        let value = Value::parse(&self.0).expect("a JsonStr contains invalid json");
+
+        let mut json = String::with_capacity(self.0.len());
+        value.write_canonical(&mut json)?;
+
+        Ok(JsonString(json))
+    }
+
+    /// Encode the json as CBOR.
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.0.len());
+        Value::parse(&self.0).expect("a JsonStr contains invalid json").write_cbor(&mut bytes);
+
+        bytes
+    }
+
+    /// Encode the json as MessagePack.
+    pub fn to_msgpack(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.0.len());
+        Value::parse(&self.0).expect("a JsonStr contains invalid json").write_msgpack(&mut bytes);
+
+        bytes
+    }
+
+    /// Convert the json to block-style YAML.
+    pub fn to_yaml(&self) -> String {
+        let mut yaml = String::with_capacity(self.0.len());
+        Value::parse(&self.0).expect("a JsonStr contains invalid json").write_yaml(&mut yaml);
+
+        yaml
+    }
+
+    /// Find object members whose key is already used by an earlier member of the same object.
+    ///
+    /// Duplicate keys are valid json, but most parsers only keep one of their values,
+    /// so they're usually a mistake.
+    pub fn duplicate_keys(&self) -> Vec<DuplicateKey> {
+        Value::parse(&self.0).expect("a JsonStr contains invalid json").duplicate_keys()
+    }
+}
+
+impl Deref for JsonStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for JsonStr {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<JsonStr> for JsonStr {
+    fn as_ref(&self) -> &JsonStr {
+        self
+    }
+}
+
+impl ToOwned for JsonStr {
+    type Owned = JsonString;
+
+    fn to_owned(&self) -> JsonString {
+        JsonString(self.0.to_owned())
+    }
+}
+
+impl fmt::Debug for JsonStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Display for JsonStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// An owned string that's valid json.
+///
+/// This is what the `json_str` and `json_fn` macros return.
+/// It dereferences to a `JsonStr`, and from there to a `str`.
+///
+/// # Examples
+///
+/// ```
+/// use json_str::JsonString;
+///
+/// let json: JsonString = "{\"a\":1}".parse().unwrap();
+///
+/// assert!("{a:1}".parse::<JsonString>().is_err());
+/// ```
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct JsonString(String);
+
+impl JsonString {
+    /// Validate a string as json.
+    pub fn from_string(json: String) -> Result<JsonString, ParseError> {
+        Value::parse(&json)?;
+
+        Ok(JsonString(json))
+    }
+
+    /// Use a string as json without validating it.
+    ///
+    /// The caller is responsible for making sure the string is valid json.
+    pub fn from_string_unchecked(json: String) -> JsonString {
+        JsonString(json)
+    }
+
+    /// Get the json as a `str`.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Get the json as a `JsonStr`.
+    pub fn as_json_str(&self) -> &JsonStr {
+        JsonStr::from_str_unchecked(&self.0)
+    }
+
+    /// Convert the json into a `String`.
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl FromStr for JsonString {
+    type Err = ParseError;
+
+    fn from_str(json: &str) -> Result<Self, Self::Err> {
+        JsonStr::from_str(json).map(|json| json.to_owned())
+    }
+}
+
+impl Deref for JsonString {
+    type Target = JsonStr;
+
+    fn deref(&self) -> &JsonStr {
+        self.as_json_str()
+    }
+}
+
+impl AsRef<str> for JsonString {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<JsonStr> for JsonString {
+    fn as_ref(&self) -> &JsonStr {
+        self.as_json_str()
+    }
+}
+
+impl Borrow<JsonStr> for JsonString {
+    fn borrow(&self) -> &JsonStr {
+        self.as_json_str()
+    }
+}
+
+impl<'a> From<&'a JsonStr> for JsonString {
+    fn from(json: &'a JsonStr) -> Self {
+        json.to_owned()
+    }
+}
+
+impl From<JsonString> for String {
+    fn from(json: JsonString) -> Self {
+        json.0
+    }
+}
+
+impl fmt::Debug for JsonString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Display for JsonString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+macro_rules! impl_eq {
+    ($lhs:ty, $rhs:ty) => {
+        impl<'a> PartialEq<$rhs> for $lhs {
+            fn eq(&self, other: &$rhs) -> bool {
+                PartialEq::eq(&self[..], &other[..])
+            }
+        }
+
+        impl<'a> PartialEq<$lhs> for $rhs {
+            fn eq(&self, other: &$lhs) -> bool {
+                PartialEq::eq(&self[..], &other[..])
+            }
+        }
+    }
+}
+
+impl_eq!(JsonString, str);
+impl_eq!(JsonString, &'a str);
+impl_eq!(JsonString, String);
+impl_eq!(JsonStr, str);
+impl_eq!(&'a JsonStr, str);
+impl_eq!(JsonStr, String);
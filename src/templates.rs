@@ -0,0 +1,483 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use fragments::{Fragments, MissingPlaceholder, Resolver};
+use parse::{self, ParseOptions};
+use value::{ParseError, Value};
+use JsonString;
+
+/// A set of json templates loaded from a directory.
+///
+/// Each `.json` file in the directory or its subdirectories is sanitised and parsed into
+/// fragments when the set is loaded, with the same options as the macros.
+/// Templates that aren't valid json, treating placeholders and partials as values, fail to load.
+/// Templates are named by their path relative to the directory, without the `.json` extension,
+/// so `queries/search.json` is named `queries/search`.
+///
+/// Templates can use `$placeholder` markers like `json_fn`, and call other templates in the set
+/// as partials, like `@queries/match(field, value)`.
+/// Arguments are matched to the placeholders of a partial by name, so `@queries/match(field, value)`
+/// gives the partial's `$field` and `$value` placeholders the values of the caller's `field` and `value`.
+/// Rendered json is validated, because values are spliced in as-is.
+///
+/// # Examples
+///
+/// ```no_run
+/// use json_str::templates::{TemplateSet, Values};
+///
+/// let mut templates = TemplateSet::load("templates").unwrap();
+///
+/// let mut values = Values::new();
+/// values.insert("qry", "*");
+/// values.insert("size", &10);
+///
+/// let json = templates.render("search", &values).unwrap();
+///
+/// // Later on, pick up any changes made to the files
+/// templates.reload_if_changed().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct TemplateSet {
+    dir: PathBuf,
+    templates: BTreeMap<String, Template>
+}
+
+#[derive(Debug)]
+struct Template {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    fragments: Fragments
+}
+
+impl TemplateSet {
+    /// Load all of the templates in a directory.
+    ///
+    /// A template that isn't valid json is an `io::ErrorKind::InvalidData` error.
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        let templates = load_templates(&dir)?;
+
+        Ok(TemplateSet {
+            dir,
+            templates
+        })
+    }
+
+    /// Get a template by name.
+    pub fn get(&self, name: &str) -> Option<&Fragments> {
+        self.templates.get(name).map(|t| &t.fragments)
+    }
+
+    /// Get the names of all the templates in the set.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.templates.keys().map(|k| k.as_ref())
+    }
+
+    /// Render a template by name, looking up placeholder values with the given resolver.
+    pub fn render<R>(&self, name: &str, resolver: &R) -> Result<JsonString, RenderError>
+        where R: Resolver + ?Sized
+    {
+        let mut json = String::new();
+        self.render_into(name, &ResolverRef(resolver), &mut Vec::new(), &mut json)?;
+
+        JsonString::from_string(json).map_err(RenderError::InvalidJson)
+    }
+
+    fn render_into<'a>(&'a self, name: &'a str, resolver: &dyn Resolver, stack: &mut Vec<&'a str>, json: &mut String) -> Result<(), RenderError> {
+        let template = self.templates.get(name).ok_or_else(|| RenderError::UnknownTemplate(name.to_owned()))?;
+
+        if stack.contains(&name) {
+            return Err(RenderError::RecursivePartial(name.to_owned()));
+        }
+
+        stack.push(name);
+
+        template.fragments.render_into(resolver, &mut |partial, args, json| {
+            let (partial, _) = self.templates
+                .get_key_value(partial)
+                .ok_or_else(|| RenderError::UnknownTemplate(partial.to_owned()))?;
+
+            // The partial can only see the placeholders it was called with
+            let args = PartialArgs {
+                args,
+                resolver
+            };
+
+            self.render_into(partial, &args, stack, json)
+        }, json)?;
+
+        stack.pop();
+
+        Ok(())
+    }
+
+    /// Reload any templates whose files have been added, changed or removed since they were loaded.
+    ///
+    /// This needs to be called periodically to pick up changes.
+    /// Returns `true` if any templates were reloaded.
+    pub fn reload_if_changed(&mut self) -> io::Result<bool> {
+        let mut changed = false;
+
+        let mut paths = Vec::new();
+        template_paths(&self.dir, &mut paths)?;
+
+        // Check for changed or added templates
+        for path in &paths {
+            let name = template_name(&self.dir, path);
+            let modified = fs::metadata(path)?.modified().ok();
+
+            let stale = match self.templates.get(&name) {
+                Some(template) => modified.is_none() || template.modified != modified,
+                None => true
+            };
+
+            if stale {
+                self.templates.insert(name, load_template(path)?);
+                changed = true;
+            }
+        }
+
+        // Check for removed templates
+        let len = self.templates.len();
+        self.templates.retain(|_, t| paths.contains(&t.path));
+        changed |= len != self.templates.len();
+
+        Ok(changed)
+    }
+}
+
+// A sized wrapper for resolvers that might not be.
+struct ResolverRef<'a, R: ?Sized + 'a>(&'a R);
+
+impl<'a, R> Resolver for ResolverRef<'a, R>
+    where R: Resolver + ?Sized
+{
+    fn resolve(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.0.resolve(name)
+    }
+
+    fn resolve_str(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.0.resolve_str(name)
+    }
+}
+
+// A resolver for the placeholders of a partial, which are the args it was called with.
+struct PartialArgs<'a> {
+    args: &'a [String],
+    resolver: &'a dyn Resolver
+}
+
+impl<'a> PartialArgs<'a> {
+    fn has_arg(&self, name: &str) -> bool {
+        self.args.iter().any(|arg| arg == name)
+    }
+}
+
+impl<'a> Resolver for PartialArgs<'a> {
+    fn resolve(&self, name: &str) -> Option<Cow<'_, str>> {
+        if self.has_arg(name) {
+            self.resolver.resolve(name)
+        }
+        else {
+            None
+        }
+    }
+
+    fn resolve_str(&self, name: &str) -> Option<Cow<'_, str>> {
+        if self.has_arg(name) {
+            self.resolver.resolve_str(name)
+        }
+        else {
+            None
+        }
+    }
+}
+
+fn load_templates(dir: &Path) -> io::Result<BTreeMap<String, Template>> {
+    let mut paths = Vec::new();
+    template_paths(dir, &mut paths)?;
+
+    let mut templates = BTreeMap::new();
+    for path in paths {
+        templates.insert(template_name(dir, &path), load_template(&path)?);
+    }
+
+    Ok(templates)
+}
+
+fn load_template(path: &Path) -> io::Result<Template> {
+    let json = fs::read_to_string(path)?;
+    let modified = fs::metadata(path)?.modified().ok();
+
+    let fragments = Fragments::parse_with(&json, &ParseOptions::strict());
+
+    if let Err(err) = check_json(&fragments) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("template '{}' is invalid json: {}", path.display(), err)));
+    }
+
+    Ok(Template {
+        path: path.to_owned(),
+        modified,
+        fragments
+    })
+}
+
+// Check a template is valid json, using `null` for any placeholders or partials.
+pub(crate) fn check_json(fragments: &Fragments) -> Result<(), ParseError> {
+    let mut sample = String::new();
+    let _ = fragments.render_into(&|_: &str| Some("null"), &mut |_, _, json: &mut String| {
+        json.push_str("null");
+        Ok::<(), MissingPlaceholder>(())
+    }, &mut sample);
+
+    Value::parse(&sample).map(|_| ())
+}
+
+// Find all `.json` files in a directory and its subdirectories.
+pub(crate) fn template_paths(dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.path());
+
+    for entry in entries {
+        let path = entry.path();
+
+        if entry.file_type()?.is_dir() {
+            template_paths(&path, paths)?;
+        }
+        else if path.extension().map(|ext| ext == "json").unwrap_or(false) {
+            paths.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+// Get the name of a template from its path relative to the template directory.
+pub(crate) fn template_name(dir: &Path, path: &Path) -> String {
+    let path = path.strip_prefix(dir).unwrap_or(path).with_extension("");
+
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// A value that can be substituted into a template.
+pub trait TemplateValue {
+    /// Push the value as json.
+    fn write_json(&self, json: &mut String);
+
+    /// Push the value as plain text, for when it's in key position or interpolated into a string.
+    ///
+    /// By default, this is the same as the json value.
+    fn write_str(&self, s: &mut String) {
+        self.write_json(s)
+    }
+
+    /// Push the plain text value as the contents of a json string, escaping it.
+    fn write_escaped(&self, json: &mut String) {
+        let mut s = String::new();
+        self.write_str(&mut s);
+
+        parse::escape(&s, json);
+    }
+}
+
+impl TemplateValue for str {
+    fn write_json(&self, json: &mut String) {
+        json.push('"');
+        parse::escape(self, json);
+        json.push('"');
+    }
+
+    fn write_str(&self, s: &mut String) {
+        s.push_str(self)
+    }
+}
+
+impl TemplateValue for String {
+    fn write_json(&self, json: &mut String) {
+        self[..].write_json(json)
+    }
+
+    fn write_str(&self, s: &mut String) {
+        self[..].write_str(s)
+    }
+}
+
+impl<T: TemplateValue + ?Sized> TemplateValue for &T {
+    fn write_json(&self, json: &mut String) {
+        (**self).write_json(json)
+    }
+
+    fn write_str(&self, s: &mut String) {
+        (**self).write_str(s)
+    }
+}
+
+impl<T: TemplateValue> TemplateValue for Option<T> {
+    fn write_json(&self, json: &mut String) {
+        match *self {
+            Some(ref v) => v.write_json(json),
+            None => json.push_str("null")
+        }
+    }
+
+    fn write_str(&self, s: &mut String) {
+        match *self {
+            Some(ref v) => v.write_str(s),
+            None => s.push_str("null")
+        }
+    }
+}
+
+impl<T: TemplateValue> TemplateValue for [T] {
+    fn write_json(&self, json: &mut String) {
+        json.push('[');
+
+        for (i, v) in self.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+
+            v.write_json(json);
+        }
+
+        json.push(']');
+    }
+}
+
+impl<T: TemplateValue> TemplateValue for Vec<T> {
+    fn write_json(&self, json: &mut String) {
+        self[..].write_json(json)
+    }
+}
+
+macro_rules! impl_template_value_display {
+    ($($t:ty),*) => {
+        $(
+            impl TemplateValue for $t {
+                fn write_json(&self, json: &mut String) {
+                    json.push_str(&self.to_string())
+                }
+            }
+        )*
+    }
+}
+
+impl_template_value_display!(bool, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+macro_rules! impl_template_value_float {
+    ($($t:ty),*) => {
+        $(
+            impl TemplateValue for $t {
+                //Json has no `NaN` or infinities, so they're written as `null`
+                fn write_json(&self, json: &mut String) {
+                    if self.is_finite() {
+                        json.push_str(&self.to_string())
+                    }
+                    else {
+                        json.push_str("null")
+                    }
+                }
+            }
+        )*
+    }
+}
+
+impl_template_value_float!(f32, f64);
+
+/// A json value that's substituted into a template as-is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Raw<'a>(pub &'a str);
+
+impl<'a> TemplateValue for Raw<'a> {
+    fn write_json(&self, json: &mut String) {
+        json.push_str(self.0)
+    }
+}
+
+/// A set of typed values to render a template with.
+///
+/// Strings are quoted and escaped, so they don't need to be written as json.
+/// Use `Raw` to substitute a value that's already json.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Values {
+    values: BTreeMap<String, (String, String)>
+}
+
+impl Values {
+    /// Create an empty set of values.
+    pub fn new() -> Self {
+        Values::default()
+    }
+
+    /// Set the value for a placeholder.
+    pub fn insert<V>(&mut self, name: &str, value: V)
+        where V: TemplateValue
+    {
+        let mut json = String::new();
+        value.write_json(&mut json);
+
+        let mut s = String::new();
+        value.write_str(&mut s);
+
+        self.values.insert(name.to_owned(), (json, s));
+    }
+}
+
+impl Resolver for Values {
+    fn resolve(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.values.get(name).map(|v| Cow::Borrowed(v.0.as_ref()))
+    }
+
+    fn resolve_str(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.values.get(name).map(|v| Cow::Borrowed(v.1.as_ref()))
+    }
+}
+
+/// An error rendering a template in a `TemplateSet`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenderError {
+    /// There's no template with the given name.
+    UnknownTemplate(String),
+    /// A template calls itself as a partial.
+    RecursivePartial(String),
+    /// A placeholder has no value.
+    MissingPlaceholder(MissingPlaceholder),
+    /// The values a template was rendered with made it invalid json.
+    InvalidJson(ParseError)
+}
+
+impl From<MissingPlaceholder> for RenderError {
+    fn from(err: MissingPlaceholder) -> Self {
+        RenderError::MissingPlaceholder(err)
+    }
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RenderError::UnknownTemplate(ref name) => write!(f, "template '{}' doesn't exist", name),
+            RenderError::RecursivePartial(ref name) => write!(f, "template '{}' calls itself as a partial", name),
+            RenderError::MissingPlaceholder(ref err) => err.fmt(f),
+            RenderError::InvalidJson(ref err) => write!(f, "rendered template is invalid json: {}", err)
+        }
+    }
+}
+
+impl Error for RenderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            RenderError::MissingPlaceholder(ref err) => Some(err),
+            RenderError::InvalidJson(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,1129 @@
+use std::char;
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::str::{self, FromStr};
+
+use parse;
+
+/// A parsed json value.
+///
+/// Numbers are kept as their original json text, and strings are unescaped.
+/// Object members are kept in the order they appear, including any duplicate keys.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>)
+}
+
+impl Value {
+    /// Parse a strict json value.
+    ///
+    /// The input must be a single json value, optionally surrounded by whitespace.
+    /// Arrays and objects can be nested up to 128 deep, so untrusted input can't overflow the stack.
+    pub fn parse(json: &str) -> Result<Value, ParseError> {
+        let mut parser = Parser {
+            input: json.as_bytes(),
+            pos: 0,
+            depth: 0
+        };
+
+        parser.whitespace();
+        let value = parser.value()?;
+        parser.whitespace();
+
+        if parser.pos < parser.input.len() {
+            return Err(parser.err("expected end of input"));
+        }
+
+        Ok(value)
+    }
+
+    /// Parse a sequence of strict json values, like newline-delimited json or a json text sequence from RFC 7464.
+    ///
+    /// Each value must be followed by whitespace, a record separator, or the end of the input.
+    pub fn parse_sequence(json: &str) -> Result<Vec<Value>, ParseError> {
+        let mut parser = Parser {
+            input: json.as_bytes(),
+            pos: 0,
+            depth: 0
+        };
+
+        let mut values = Vec::new();
+
+        loop {
+            parser.separators();
+
+            if parser.pos == parser.input.len() {
+                return Ok(values);
+            }
+
+            values.push(parser.value()?);
+
+            match parser.peek() {
+                Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') | Some(0x1e) | None => (),
+                _ => return Err(parser.err("expected whitespace or a record separator"))
+            }
+        }
+    }
+
+    /// Write the value as canonical json, per RFC 8785.
+    ///
+    /// Object members are sorted by the UTF-16 code units of their keys, numbers are written in
+    /// their shortest form like ECMAScript, and strings only escape what they have to.
+    /// Numbers that can't be represented by a double are an error.
+    pub fn write_canonical(&self, json: &mut String) -> Result<(), CanonicalError> {
+        match *self {
+            Value::Number(ref n) => {
+                let n = canonical_number(n).ok_or_else(|| CanonicalError {
+                    number: n.clone()
+                })?;
+
+                json.push_str(&n);
+            },
+            Value::Array(ref values) => {
+                json.push('[');
+
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+
+                    v.write_canonical(json)?;
+                }
+
+                json.push(']');
+            },
+            Value::Object(ref members) => {
+                let mut members: Vec<_> = members.iter().collect();
+                members.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+
+                json.push('{');
+
+                for (i, (k, v)) in members.into_iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+
+                    json.push('"');
+                    parse::escape(k, json);
+                    json.push_str("\":");
+                    v.write_canonical(json)?;
+                }
+
+                json.push('}');
+            },
+            ref v => v.write(json)
+        }
+
+        Ok(())
+    }
+
+    /// Expand object keys written as dotted paths, like `"query.bool"`, into nested objects.
+    ///
+    /// Objects with the same key are merged, so paths that share a prefix end up in the same object.
+    /// Keys with empty segments, like `"a..b"`, are left alone.
+    pub fn expand_key_paths(self) -> Value {
+        match self {
+            Value::Array(values) => Value::Array(values.into_iter().map(Value::expand_key_paths).collect()),
+            Value::Object(members) => {
+                let mut expanded = Vec::new();
+
+                for (key, value) in members {
+                    let value = value.expand_key_paths();
+
+                    if !key.contains('.') || key.split('.').any(str::is_empty) {
+                        merge_member(&mut expanded, key, value);
+                        continue;
+                    }
+
+                    // Nest the value under each segment of the path, starting from the last
+                    let mut segments = key.split('.').map(|s| s.to_owned()).collect::<Vec<_>>();
+                    let first = segments.remove(0);
+                    let value = segments
+                        .into_iter()
+                        .rev()
+                        .fold(value, |value, segment| Value::Object(vec![(segment, value)]));
+
+                    merge_member(&mut expanded, first, value);
+                }
+
+                Value::Object(expanded)
+            },
+            value => value
+        }
+    }
+
+    /// Find object members whose key is already used by an earlier member of the same object.
+    ///
+    /// Each duplicate is returned in the order it appears, along with a JSON Pointer
+    /// to the object it's in, like `/query/bool/filter/0`.
+    pub fn duplicate_keys(&self) -> Vec<DuplicateKey> {
+        let mut duplicates = Vec::new();
+        self.find_duplicate_keys(&mut String::new(), &mut duplicates);
+
+        duplicates
+    }
+
+    fn find_duplicate_keys(&self, path: &mut String, duplicates: &mut Vec<DuplicateKey>) {
+        let len = path.len();
+
+        match *self {
+            Value::Array(ref values) => {
+                for (i, v) in values.iter().enumerate() {
+                    let _ = write!(path, "/{}", i);
+                    v.find_duplicate_keys(path, duplicates);
+                    path.truncate(len);
+                }
+            },
+            Value::Object(ref members) => {
+                for (i, (k, v)) in members.iter().enumerate() {
+                    if members[..i].iter().any(|(seen, _)| seen == k) {
+                        duplicates.push(DuplicateKey::new(path.clone(), k.clone()));
+                    }
+
+                    path.push('/');
+                    path.push_str(&k.replace('~', "~0").replace('/', "~1"));
+                    v.find_duplicate_keys(path, duplicates);
+                    path.truncate(len);
+                }
+            },
+            _ => ()
+        }
+    }
+
+    /// Remove members whose key is already used by another member of the same object.
+    ///
+    /// With `Keep::First`, the first member with a key is kept.
+    /// With `Keep::Last`, the value of the last member with a key is kept, but in the position
+    /// of the first one, which is what `JSON.parse` does.
+    pub fn dedup_keys(self, keep: Keep) -> Value {
+        match self {
+            Value::Array(values) => Value::Array(values.into_iter().map(|v| v.dedup_keys(keep)).collect()),
+            Value::Object(members) => {
+                let mut deduped: Vec<(String, Value)> = Vec::with_capacity(members.len());
+
+                for (key, value) in members {
+                    let value = value.dedup_keys(keep);
+
+                    match (deduped.iter().position(|(k, _)| *k == key), keep) {
+                        (Some(_), Keep::First) => (),
+                        (Some(i), Keep::Last) => deduped[i].1 = value,
+                        (None, _) => deduped.push((key, value))
+                    }
+                }
+
+                Value::Object(deduped)
+            },
+            value => value
+        }
+    }
+
+    /// Write the value as minified json.
+    pub fn write(&self, json: &mut String) {
+        match *self {
+            Value::Null => json.push_str("null"),
+            Value::Bool(true) => json.push_str("true"),
+            Value::Bool(false) => json.push_str("false"),
+            Value::Number(ref n) => json.push_str(n),
+            Value::String(ref s) => {
+                json.push('"');
+                parse::escape(s, json);
+                json.push('"');
+            },
+            Value::Array(ref values) => {
+                json.push('[');
+
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+
+                    v.write(json);
+                }
+
+                json.push(']');
+            },
+            Value::Object(ref members) => {
+                json.push('{');
+
+                for (i, (k, v)) in members.iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+
+                    json.push('"');
+                    parse::escape(k, json);
+                    json.push_str("\":");
+                    v.write(json);
+                }
+
+                json.push('}');
+            }
+        }
+    }
+
+    /// Write the value as CBOR, per RFC 8949.
+    ///
+    /// Integers that fit in 64 bits are written as integers, and other numbers as the smallest float
+    /// that holds them exactly, from a half to a double.
+    pub fn write_cbor(&self, bytes: &mut Vec<u8>) {
+        match *self {
+            Value::Null => bytes.push(0xf6),
+            Value::Bool(true) => bytes.push(0xf5),
+            Value::Bool(false) => bytes.push(0xf4),
+            Value::Number(ref n) => match Number::parse(n) {
+                Number::Int(i) if i < 0 => cbor_head(1, !(i as u64), bytes),
+                Number::Int(i) => cbor_head(0, i as u64, bytes),
+                Number::UInt(u) => cbor_head(0, u, bytes),
+                Number::Float(f) if f64::from(f as f32) == f || f.is_nan() => match cbor_half(f as f32) {
+                    Some(half) => {
+                        bytes.push(0xf9);
+                        bytes.extend_from_slice(&half.to_be_bytes());
+                    },
+                    None => {
+                        bytes.push(0xfa);
+                        bytes.extend_from_slice(&(f as f32).to_be_bytes());
+                    }
+                },
+                Number::Float(f) => {
+                    bytes.push(0xfb);
+                    bytes.extend_from_slice(&f.to_be_bytes());
+                }
+            },
+            Value::String(ref s) => {
+                cbor_head(3, s.len() as u64, bytes);
+                bytes.extend_from_slice(s.as_bytes());
+            },
+            Value::Array(ref values) => {
+                cbor_head(4, values.len() as u64, bytes);
+
+                for v in values {
+                    v.write_cbor(bytes);
+                }
+            },
+            Value::Object(ref members) => {
+                cbor_head(5, members.len() as u64, bytes);
+
+                for (k, v) in members {
+                    cbor_head(3, k.len() as u64, bytes);
+                    bytes.extend_from_slice(k.as_bytes());
+                    v.write_cbor(bytes);
+                }
+            }
+        }
+    }
+
+    /// Write the value as MessagePack.
+    ///
+    /// Integers that fit in 64 bits are written as integers, and other numbers as the smallest float
+    /// that holds them exactly, or as a double.
+    pub fn write_msgpack(&self, bytes: &mut Vec<u8>) {
+        match *self {
+            Value::Null => bytes.push(0xc0),
+            Value::Bool(true) => bytes.push(0xc3),
+            Value::Bool(false) => bytes.push(0xc2),
+            Value::Number(ref n) => match Number::parse(n) {
+                Number::Int(i) if i < 0 => msgpack_int(i, bytes),
+                Number::Int(i) => msgpack_uint(i as u64, bytes),
+                Number::UInt(u) => msgpack_uint(u, bytes),
+                Number::Float(f) if f64::from(f as f32) == f || f.is_nan() => {
+                    bytes.push(0xca);
+                    bytes.extend_from_slice(&(f as f32).to_be_bytes());
+                },
+                Number::Float(f) => {
+                    bytes.push(0xcb);
+                    bytes.extend_from_slice(&f.to_be_bytes());
+                }
+            },
+            Value::String(ref s) => msgpack_str(s, bytes),
+            Value::Array(ref values) => {
+                msgpack_head(values.len(), 0x90, 0xdc, bytes);
+
+                for v in values {
+                    v.write_msgpack(bytes);
+                }
+            },
+            Value::Object(ref members) => {
+                msgpack_head(members.len(), 0x80, 0xde, bytes);
+
+                for (k, v) in members {
+                    msgpack_str(k, bytes);
+                    v.write_msgpack(bytes);
+                }
+            }
+        }
+    }
+
+    /// Write the value as block-style YAML.
+    ///
+    /// Strings that YAML would read as something else, like `yes`, `null` or `1e3`, are quoted,
+    /// and numbers are written so YAML 1.1 and 1.2 parsers both read them as numbers.
+    pub fn write_yaml(&self, yaml: &mut String) {
+        self.write_yaml_block(0, yaml);
+    }
+
+    // Write the value starting on the current line, with any following lines at the given indent.
+    fn write_yaml_block(&self, indent: usize, yaml: &mut String) {
+        match *self {
+            Value::Array(ref values) if !values.is_empty() => {
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        yaml.extend((0..indent).map(|_| ' '));
+                    }
+
+                    //Nested blocks start on the same line as the dash
+                    yaml.push_str("- ");
+                    v.write_yaml_block(indent + 2, yaml);
+                }
+            },
+            Value::Object(ref members) if !members.is_empty() => {
+                for (i, (k, v)) in members.iter().enumerate() {
+                    if i > 0 {
+                        yaml.extend((0..indent).map(|_| ' '));
+                    }
+
+                    yaml_str(k, yaml);
+                    yaml.push(':');
+
+                    if v.is_yaml_block() {
+                        yaml.push('\n');
+                        yaml.extend((0..indent + 2).map(|_| ' '));
+                        v.write_yaml_block(indent + 2, yaml);
+                    }
+                    else {
+                        yaml.push(' ');
+                        v.write_yaml_block(indent + 2, yaml);
+                    }
+                }
+            },
+            Value::Array(_) => yaml.push_str("[]\n"),
+            Value::Object(_) => yaml.push_str("{}\n"),
+            Value::Null => yaml.push_str("null\n"),
+            Value::Bool(true) => yaml.push_str("true\n"),
+            Value::Bool(false) => yaml.push_str("false\n"),
+            Value::Number(ref n) => {
+                yaml_number(n, yaml);
+                yaml.push('\n');
+            },
+            Value::String(ref s) => {
+                yaml_str(s, yaml);
+                yaml.push('\n');
+            }
+        }
+    }
+
+    fn is_yaml_block(&self) -> bool {
+        match *self {
+            Value::Array(ref values) => !values.is_empty(),
+            Value::Object(ref members) => !members.is_empty(),
+            _ => false
+        }
+    }
+}
+
+// Write a json number so it's a float in both YAML 1.1 and 1.2.
+// YAML 1.1 needs a `.` in the mantissa and a sign in the exponent, like `1.0e+3`.
+fn yaml_number(n: &str, yaml: &mut String) {
+    let (mantissa, exp) = match n.find(['e', 'E']) {
+        Some(i) => (&n[..i], &n[i + 1..]),
+        None => {
+            yaml.push_str(n);
+            return;
+        }
+    };
+
+    yaml.push_str(mantissa);
+    if !mantissa.contains('.') {
+        yaml.push_str(".0");
+    }
+
+    yaml.push('e');
+    if !exp.starts_with(['+', '-']) {
+        yaml.push('+');
+    }
+    yaml.push_str(exp);
+}
+
+// Write a string as a plain YAML scalar, or a double quoted one if it would be read as something else.
+fn yaml_str(s: &str, yaml: &mut String) {
+    const SPECIAL: &[&str] = &["null", "~", "true", "false", "yes", "no", "on", "off", "y", "n", ".inf", "-.inf", "+.inf", ".nan"];
+
+    let bytes = s.as_bytes();
+    let lower = s.to_lowercase();
+
+    let quote = match bytes.first() {
+        None => true,
+        //Indicators, or the start of something that might be a number or a date
+        Some(&c) if b"-?:,[]{}#&*!|>'\"%@` \t".contains(&c) || c.is_ascii_digit() => true,
+        Some(&b'+') | Some(&b'.') => bytes.get(1).is_some_and(|c| c.is_ascii_digit()),
+        _ => false
+    };
+
+    let quote = quote
+        || SPECIAL.contains(&&*lower)
+        || s.ends_with([' ', '\t', ':'])
+        || s.contains(": ")
+        || s.contains(" #")
+        || s.chars().any(char::is_control);
+
+    if quote {
+        yaml.push('"');
+
+        for c in s.chars() {
+            match c {
+                //Json doesn't escape these, but YAML needs them to be
+                '\u{7f}'..='\u{9f}' => {
+                    let _ = write!(yaml, "\\u{:04x}", c as u32);
+                },
+                c => parse::escape(c.encode_utf8(&mut [0; 4]), yaml)
+            }
+        }
+
+        yaml.push('"');
+    }
+    else {
+        yaml.push_str(s);
+    }
+}
+
+// A json number, as the kind of number binary formats can write.
+enum Number {
+    Int(i64),
+    UInt(u64),
+    Float(f64)
+}
+
+impl Number {
+    fn parse(n: &str) -> Number {
+        if !n.contains(['.', 'e', 'E']) {
+            if let Ok(i) = n.parse() {
+                return Number::Int(i);
+            }
+
+            if let Ok(u) = n.parse() {
+                return Number::UInt(u);
+            }
+        }
+
+        //Json numbers always parse as a float, even if they're out of range
+        Number::Float(n.parse().unwrap_or(f64::NAN))
+    }
+}
+
+// Get the bits of a half-precision float that holds a float exactly.
+// Returns `None` if a half is too small or too imprecise for it.
+fn cbor_half(f: f32) -> Option<u16> {
+    let bits = f.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    match exp {
+        //Infinity, or NaN which is written as a quiet NaN
+        0xff if mantissa == 0 => Some(sign | 0x7c00),
+        0xff => Some(0x7e00),
+        //Zero
+        0 if mantissa == 0 => Some(sign),
+        //Subnormal singles are too small for a half
+        0 => None,
+        _ => {
+            let exp = exp - 127;
+            let mantissa = mantissa | 0x80_0000;
+
+            if (-14..=15).contains(&exp) {
+                //A normal half keeps the top 10 bits of the mantissa
+                if mantissa & 0x1fff == 0 {
+                    Some(sign | ((exp + 15) as u16) << 10 | ((mantissa >> 13) & 0x3ff) as u16)
+                }
+                else {
+                    None
+                }
+            }
+            else if (-24..-14).contains(&exp) {
+                //A subnormal half is a multiple of 2^-24
+                let shift = -(exp + 1) as u32;
+
+                if mantissa & ((1 << shift) - 1) == 0 {
+                    Some(sign | (mantissa >> shift) as u16)
+                }
+                else {
+                    None
+                }
+            }
+            else {
+                None
+            }
+        }
+    }
+}
+
+// Write the head of a CBOR data item, which is its major type and an argument.
+fn cbor_head(major: u8, arg: u64, bytes: &mut Vec<u8>) {
+    let major = major << 5;
+
+    if arg < 24 {
+        bytes.push(major | arg as u8);
+    }
+    else if arg <= u64::from(u8::MAX) {
+        bytes.push(major | 24);
+        bytes.push(arg as u8);
+    }
+    else if arg <= u64::from(u16::MAX) {
+        bytes.push(major | 25);
+        bytes.extend_from_slice(&(arg as u16).to_be_bytes());
+    }
+    else if arg <= u64::from(u32::MAX) {
+        bytes.push(major | 26);
+        bytes.extend_from_slice(&(arg as u32).to_be_bytes());
+    }
+    else {
+        bytes.push(major | 27);
+        bytes.extend_from_slice(&arg.to_be_bytes());
+    }
+}
+
+fn msgpack_uint(u: u64, bytes: &mut Vec<u8>) {
+    if u < 0x80 {
+        bytes.push(u as u8);
+    }
+    else if u <= u64::from(u8::MAX) {
+        bytes.push(0xcc);
+        bytes.push(u as u8);
+    }
+    else if u <= u64::from(u16::MAX) {
+        bytes.push(0xcd);
+        bytes.extend_from_slice(&(u as u16).to_be_bytes());
+    }
+    else if u <= u64::from(u32::MAX) {
+        bytes.push(0xce);
+        bytes.extend_from_slice(&(u as u32).to_be_bytes());
+    }
+    else {
+        bytes.push(0xcf);
+        bytes.extend_from_slice(&u.to_be_bytes());
+    }
+}
+
+fn msgpack_int(i: i64, bytes: &mut Vec<u8>) {
+    if i >= -32 {
+        bytes.push(i as u8);
+    }
+    else if i >= i64::from(i8::MIN) {
+        bytes.push(0xd0);
+        bytes.push(i as u8);
+    }
+    else if i >= i64::from(i16::MIN) {
+        bytes.push(0xd1);
+        bytes.extend_from_slice(&(i as i16).to_be_bytes());
+    }
+    else if i >= i64::from(i32::MIN) {
+        bytes.push(0xd2);
+        bytes.extend_from_slice(&(i as i32).to_be_bytes());
+    }
+    else {
+        bytes.push(0xd3);
+        bytes.extend_from_slice(&i.to_be_bytes());
+    }
+}
+
+fn msgpack_str(s: &str, bytes: &mut Vec<u8>) {
+    let len = s.len();
+
+    if len < 32 {
+        bytes.push(0xa0 | len as u8);
+    }
+    else if len <= usize::from(u8::MAX) {
+        bytes.push(0xd9);
+        bytes.push(len as u8);
+    }
+    else if len <= usize::from(u16::MAX) {
+        bytes.push(0xda);
+        bytes.extend_from_slice(&(len as u16).to_be_bytes());
+    }
+    else {
+        bytes.push(0xdb);
+        bytes.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+
+    bytes.extend_from_slice(s.as_bytes());
+}
+
+// Write the head of a MessagePack array or map, using the fixed size marker if it fits.
+fn msgpack_head(len: usize, fixed: u8, sized: u8, bytes: &mut Vec<u8>) {
+    if len < 16 {
+        bytes.push(fixed | len as u8);
+    }
+    else if len <= usize::from(u16::MAX) {
+        bytes.push(sized);
+        bytes.extend_from_slice(&(len as u16).to_be_bytes());
+    }
+    else {
+        bytes.push(sized + 1);
+        bytes.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+// Add a member to an object, merging it with an existing member if they're both objects.
+fn merge_member(members: &mut Vec<(String, Value)>, key: String, value: Value) {
+    let existing = members
+        .iter()
+        .rposition(|(k, v)| *k == key && matches!(*v, Value::Object(_)));
+
+    match (existing, value) {
+        (Some(i), Value::Object(new)) => {
+            if let Value::Object(ref mut members) = members[i].1 {
+                for (k, v) in new {
+                    merge_member(members, k, v);
+                }
+            }
+        },
+        (_, value) => members.push((key, value))
+    }
+}
+
+// Write a json number the way ECMAScript would, which is the shortest form that round-trips as a double.
+fn canonical_number(n: &str) -> Option<String> {
+    let n: f64 = n.parse().ok()?;
+
+    if !n.is_finite() {
+        return None;
+    }
+
+    if n == 0.0 {
+        return Some("0".to_owned());
+    }
+
+    // Get the shortest digits and the exponent from Rust's scientific notation
+    let sci = format!("{:e}", n.abs());
+    let (mantissa, exp) = sci.split_once('e')?;
+
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let k = digits.len() as i32;
+    let point = exp.parse::<i32>().ok()? + 1;
+
+    let mut canonical = String::new();
+    if n < 0.0 {
+        canonical.push('-');
+    }
+
+    if k <= point && point <= 21 {
+        canonical.push_str(&digits);
+        canonical.extend((k..point).map(|_| '0'));
+    }
+    else if 0 < point && point <= 21 {
+        let (int, frac) = digits.split_at(point as usize);
+
+        canonical.push_str(int);
+        canonical.push('.');
+        canonical.push_str(frac);
+    }
+    else if -6 < point && point <= 0 {
+        canonical.push_str("0.");
+        canonical.extend((point..0).map(|_| '0'));
+        canonical.push_str(&digits);
+    }
+    else {
+        let (first, rest) = digits.split_at(1);
+
+        canonical.push_str(first);
+        if !rest.is_empty() {
+            canonical.push('.');
+            canonical.push_str(rest);
+        }
+
+        canonical.push('e');
+        if point > 0 {
+            canonical.push('+');
+        }
+        canonical.push_str(&(point - 1).to_string());
+    }
+
+    Some(canonical)
+}
+
+impl FromStr for Value {
+    type Err = ParseError;
+
+    fn from_str(json: &str) -> Result<Self, Self::Err> {
+        Value::parse(json)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut json = String::new();
+        self.write(&mut json);
+
+        f.write_str(&json)
+    }
+}
+
+/// An error parsing json.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    offset: usize,
+    reason: &'static str
+}
+
+impl ParseError {
+    /// The byte offset in the input where the error was found.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}", self.reason, self.offset)
+    }
+}
+
+impl Error for ParseError {}
+
+/// An error writing canonical json.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanonicalError {
+    number: String
+}
+
+impl CanonicalError {
+    /// The number that can't be represented by a double.
+    pub fn number(&self) -> &str {
+        &self.number
+    }
+}
+
+impl fmt::Display for CanonicalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "number '{}' is out of range for canonical json", self.number)
+    }
+}
+
+impl Error for CanonicalError {}
+
+/// Which member to keep when removing duplicate keys from an object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keep {
+    /// Keep the first member with a key.
+    First,
+    /// Keep the last member with a key.
+    Last
+}
+
+/// An object has more than one member with the same key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateKey {
+    path: String,
+    key: String
+}
+
+impl DuplicateKey {
+    pub(crate) fn new(path: String, key: String) -> Self {
+        DuplicateKey {
+            path,
+            key
+        }
+    }
+
+    /// A JSON Pointer to the object with the duplicate key.
+    ///
+    /// The pointer is empty if the object is the root value.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The duplicate key.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl fmt::Display for DuplicateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "duplicate key '{}'", self.key)
+        }
+        else {
+            write!(f, "duplicate key '{}' in object at '{}'", self.key, self.path)
+        }
+    }
+}
+
+impl Error for DuplicateKey {}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    depth: usize
+}
+
+// The deepest arrays and objects can be nested.
+// Values are parsed recursively, so this stops deeply nested input from overflowing the stack.
+const MAX_DEPTH: usize = 128;
+
+impl<'a> Parser<'a> {
+    fn err(&self, reason: &'static str) -> ParseError {
+        ParseError {
+            offset: self.pos,
+            reason
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).cloned()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    fn separators(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') | Some(0x1e) = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn keyword(&mut self, keyword: &'static str, value: Value) -> Result<Value, ParseError> {
+        if self.input[self.pos..].starts_with(keyword.as_bytes()) {
+            self.pos += keyword.len();
+            Ok(value)
+        }
+        else {
+            Err(self.err("expected a value"))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            Some(b'{') => self.nested(Parser::object),
+            Some(b'[') => self.nested(Parser::array),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b't') => self.keyword("true", Value::Bool(true)),
+            Some(b'f') => self.keyword("false", Value::Bool(false)),
+            Some(b'n') => self.keyword("null", Value::Null),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.err("expected a value")),
+            None => Err(self.err("unexpected end of input"))
+        }
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Value, ParseError>) -> Result<Value, ParseError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.err("arrays and objects are nested too deeply"));
+        }
+
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+
+        value
+    }
+
+    fn object(&mut self) -> Result<Value, ParseError> {
+        self.pos += 1;
+        self.whitespace();
+
+        let mut members = Vec::new();
+
+        if self.eat(b'}') {
+            return Ok(Value::Object(members));
+        }
+
+        loop {
+            if self.peek() != Some(b'"') {
+                return Err(self.err("expected a string key"));
+            }
+
+            let key = self.string()?;
+
+            self.whitespace();
+            if !self.eat(b':') {
+                return Err(self.err("expected ':'"));
+            }
+
+            self.whitespace();
+            let value = self.value()?;
+            members.push((key, value));
+
+            self.whitespace();
+            if self.eat(b',') {
+                self.whitespace();
+            }
+            else if self.eat(b'}') {
+                return Ok(Value::Object(members));
+            }
+            else {
+                return Err(self.err("expected ',' or '}'"));
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.pos += 1;
+        self.whitespace();
+
+        let mut values = Vec::new();
+
+        if self.eat(b']') {
+            return Ok(Value::Array(values));
+        }
+
+        loop {
+            values.push(self.value()?);
+
+            self.whitespace();
+            if self.eat(b',') {
+                self.whitespace();
+            }
+            else if self.eat(b']') {
+                return Ok(Value::Array(values));
+            }
+            else {
+                return Err(self.err("expected ',' or ']'"));
+            }
+        }
+    }
+
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+
+        self.pos - start
+    }
+
+    fn number(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+
+        self.eat(b'-');
+
+        // Integer part, which can't have leading zeros
+        if !self.eat(b'0') && self.digits() == 0 {
+            return Err(self.err("expected a digit"));
+        }
+
+        // Fraction
+        if self.eat(b'.') && self.digits() == 0 {
+            return Err(self.err("expected a digit"));
+        }
+
+        // Exponent
+        if self.eat(b'e') || self.eat(b'E') {
+            if !self.eat(b'+') {
+                self.eat(b'-');
+            }
+
+            if self.digits() == 0 {
+                return Err(self.err("expected a digit"));
+            }
+        }
+
+        let n = unsafe { str::from_utf8_unchecked(&self.input[start..self.pos]) };
+
+        Ok(Value::Number(n.to_owned()))
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.pos += 1;
+
+        let mut s = String::new();
+
+        loop {
+            // Take everything up to the next quote, escape or control char
+            let start = self.pos;
+            while let Some(c) = self.peek() {
+                if c == b'"' || c == b'\\' || c < 0x20 {
+                    break;
+                }
+
+                self.pos += 1;
+            }
+
+            s.push_str(unsafe { str::from_utf8_unchecked(&self.input[start..self.pos]) });
+
+            match self.peek() {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(s);
+                },
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let c = self.escape()?;
+                    s.push(c);
+                },
+                Some(_) => return Err(self.err("unescaped control character in string")),
+                None => return Err(self.err("unterminated string"))
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, ParseError> {
+        let c = match self.peek() {
+            Some(b'"') => '"',
+            Some(b'\\') => '\\',
+            Some(b'/') => '/',
+            Some(b'b') => '\u{8}',
+            Some(b'f') => '\u{c}',
+            Some(b'n') => '\n',
+            Some(b'r') => '\r',
+            Some(b't') => '\t',
+            Some(b'u') => {
+                self.pos += 1;
+                let hi = self.hex()?;
+
+                // Surrogate pairs are escaped as two code units
+                let c = match hi {
+                    0xD800..=0xDBFF => {
+                        if !(self.eat(b'\\') && self.eat(b'u')) {
+                            return Err(self.err("expected a low surrogate"));
+                        }
+
+                        let lo = self.hex()?;
+                        if !(0xDC00..=0xDFFF).contains(&lo) {
+                            return Err(self.err("expected a low surrogate"));
+                        }
+
+                        0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00)
+                    },
+                    0xDC00..=0xDFFF => return Err(self.err("unexpected low surrogate")),
+                    c => c
+                };
+
+                return char::from_u32(c).ok_or_else(|| self.err("invalid unicode escape"));
+            },
+            _ => return Err(self.err("invalid escape"))
+        };
+
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn hex(&mut self) -> Result<u32, ParseError> {
+        let hex = match self.input.get(self.pos..self.pos + 4) {
+            Some(hex) if hex.iter().all(|c| c.is_ascii_hexdigit()) => hex,
+            _ => return Err(self.err("expected 4 hex digits"))
+        };
+
+        let c = hex.iter().fold(0, |c, h| (c << 4) + (*h as char).to_digit(16).unwrap_or(0));
+
+        self.pos += 4;
+        Ok(c)
+    }
+}
@@ -6,6 +6,7 @@
 extern crate json_str;
 
 use json_str::parse::*;
+use json_str::{JsonStr, JsonString};
 
 #[test]
 fn can_generate_json() {
@@ -72,6 +73,27 @@ fn sanitisation_does_not_affect_strings() {
     assert_eq!("{\"a\":\"stuff and data.\n	More.\",\"b\":\"色は匂へど 散りぬるを\"}", &sanitised);
 }
 
+#[test]
+fn strict_sanitisation_escapes_control_chars_in_strings() {
+    let j = "\n{ \"a\" : \"stuff and data.\n\tMore.\u{1}\", b: r\"raw\n\"}";
+
+    let mut sanitised = String::new();
+    parse_literal_with(j.as_bytes(), &mut sanitised, &ParseOptions::strict());
+
+    assert_eq!("{\"a\":\"stuff and data.\\n\\tMore.\\u0001\",\"b\":\"raw\\n\"}", &sanitised);
+}
+
+#[test]
+fn can_generate_json_from_multi_line_strings() {
+    let j = json_str!({
+        script: "if (a) {
+	return b;
+}"
+    });
+
+    assert_eq!("{\"script\":\"if (a) {\\n\\treturn b;\\n}\"}", j);
+}
+
 #[test]
 fn sanitisation_recognises_escaped_strings() {
     let j = r#"{"a":"a \"quoted'\" string'. \"\\"}"#;
@@ -92,6 +114,61 @@ fn sanitisation_standardises_quotes() {
     assert_eq!("{\"a\":\"stuff\",\"b\":{\"c\":[\"0\",1]},\"d\":14}", &sanitised);
 }
 
+#[test]
+fn sanitisation_escapes_double_quotes_in_single_quoted_strings() {
+    let j = "{ 'a' : 'say \"hi\"', 'b': 'it\\'s' }";
+
+    let mut sanitised = String::new();
+    parse_literal(j.as_bytes(), &mut sanitised);
+
+    assert_eq!("{\"a\":\"say \\\"hi\\\"\",\"b\":\"it's\"}", &sanitised);
+}
+
+#[test]
+fn sanitisation_distinguishes_lifetimes_from_single_quoted_strings() {
+    let j = "{ 'a': 'static, 'b': ['x', 'it is'] }";
+
+    let mut sanitised = String::new();
+    parse_literal(j.as_bytes(), &mut sanitised);
+
+    assert_eq!("{\"a\":\"'static\",\"b\":[\"x\",\"it is\"]}", &sanitised);
+}
+
+#[test]
+fn sanitisation_keeps_single_quoted_strings_that_start_like_lifetimes() {
+    let cases = [
+        ("{'k':'a,b'}", "{\"k\":\"a,b\"}"),
+        ("['x', 'y,z']", "[\"x\",\"y,z\"]"),
+        ("{ 'a: 1, 'b: 'static }", "{\"'a\":1,\"'b\":\"'static\"}"),
+        ("['a, 'x']", "[\"'a\",\"x\"]")
+    ];
+
+    for &(j, expected) in &cases {
+        let mut sanitised = String::new();
+        parse_literal(j.as_bytes(), &mut sanitised);
+
+        assert_eq!(expected, &sanitised);
+    }
+
+    let mut values = Vec::new();
+    parse_values(b"'a,b' ['y,z', #[cfg(any())] 'c,d', 1]", &mut values);
+
+    assert_eq!(vec!["\"a,b\"", "[\"y,z\",1]"], values);
+}
+
+#[test]
+fn can_generate_json_from_rust_char_literals() {
+    let j = json_str!({
+        a: 'x',
+        b: '"',
+        c: '\'',
+        d: '\u{1F600}',
+        e: b'y'
+    });
+
+    assert_eq!("{\"a\":\"x\",\"b\":\"\\\"\",\"c\":\"'\",\"d\":\"\\ud83d\\ude00\",\"e\":\"y\"}", j);
+}
+
 #[test]
 fn sanitisation_quotes_unquoted_keys() {
     let j = "{ a : \"stuff\", \"b\":{  c:[ 0, 1 ] },d:14 }";
@@ -102,6 +179,354 @@ fn sanitisation_quotes_unquoted_keys() {
     assert_eq!("{\"a\":\"stuff\",\"b\":{\"c\":[0,1]},\"d\":14}", &sanitised);
 }
 
+#[test]
+fn can_generate_json_with_bare_keys() {
+    let j = json_str!({
+        field1: 1,
+        _source: true,
+        r#type: "doc",
+        1: "x",
+        @timestamp: "2016-01-01",
+        user-agent: "curl",
+        geo.point: [1.5, -2]
+    });
+
+    assert_eq!("{\"field1\":1,\"_source\":true,\"type\":\"doc\",\"1\":\"x\",\"@timestamp\":\"2016-01-01\",\"user-agent\":\"curl\",\"geo.point\":[1.5,-2]}", j);
+}
+
+#[test]
+fn sanitisation_quotes_unicode_keys() {
+    let j = "{ größe: 1, 名前: \"a\" }";
+
+    let mut sanitised = String::new();
+    parse_literal(j.as_bytes(), &mut sanitised);
+
+    assert_eq!("{\"größe\":1,\"名前\":\"a\"}", &sanitised);
+}
+
+#[test]
+fn parse_fragments_distinguishes_partials_from_punctuated_keys() {
+    let mut fragments = Vec::new();
+    parse_fragments(b"{ @timestamp: @range(from, to) }", &mut fragments);
+
+    match fragments.as_slice() {
+        [JsonFragment::Literal(ref l), JsonFragment::Partial("range", ref args), JsonFragment::Literal(_)] => {
+            assert_eq!("{\"@timestamp\":", l);
+            assert_eq!(&["from", "to"], args.as_slice());
+        },
+        f => panic!("unexpected fragments {:?}", f)
+    }
+}
+
+#[test]
+fn sanitisation_expands_key_paths() {
+    let j = "{ query.bool.filter: [ { term.user: 'kimchy' } ], query.bool.must: [], 'query.size': 10, a..b: 1 }";
+
+    let mut sanitised = String::new();
+    parse_literal(j.as_bytes(), &mut sanitised);
+
+    assert_eq!("{\"query.bool.filter\":[{\"term.user\":\"kimchy\"}],\"query.bool.must\":[],\"query.size\":10,\"a..b\":1}", &sanitised);
+
+    let opts = ParseOptions {
+        expand_key_paths: true,
+        ..ParseOptions::strict()
+    };
+
+    let mut sanitised = String::new();
+    parse_literal_with(j.as_bytes(), &mut sanitised, &opts);
+
+    assert_eq!("{\"query\":{\"bool\":{\"filter\":[{\"term\":{\"user\":\"kimchy\"}}],\"must\":[]},\"size\":10},\"a..b\":1}", &sanitised);
+}
+
+#[test]
+fn parse_fragments_expands_key_paths() {
+    use json_str::fragments::Fragments;
+
+    let opts = ParseOptions {
+        expand_key_paths: true,
+        ..ParseOptions::strict()
+    };
+
+    let fragments = Fragments::parse_with("{ query.match: { $field: $qry }, query.match.boost: 2, index.name: \"logs-${date}\" }", &opts);
+
+    let json = fragments.render_with(&[("field", "title"), ("qry", "\"*\""), ("date", "2016")]).unwrap();
+
+    assert_eq!("{\"query\":{\"match\":{\"title\":\"*\",\"boost\":2}},\"index\":{\"name\":\"logs-2016\"}}", json);
+}
+
+#[test]
+fn can_generate_json_from_rust_values() {
+    let j = json_str!(#[rust_values] {
+        none: None,
+        some: Some("x"),
+        nested: Some(Some([1, 2])),
+        tuple: (1, "a", (true, None)),
+        repeat: [0; 4],
+        nested_repeat: [[Some(1); 2]; 2],
+        inclusive: 1..=3,
+        exclusive: -1..2,
+        empty: 1..1,
+        None: "key",
+        float: 1.5e-3,
+        string: "Some(1..=3)"
+    });
+
+    assert_eq!("{\"none\":null,\"some\":\"x\",\"nested\":[1,2],\"tuple\":[1,\"a\",[true,null]],\"repeat\":[0,0,0,0],\"nested_repeat\":[[1,1],[1,1]],\"inclusive\":[1,2,3],\"exclusive\":[-1,0,1],\"empty\":[],\"None\":\"key\",\"float\":1.5e-3,\"string\":\"Some(1..=3)\"}", j);
+}
+
+#[test]
+fn can_generate_replacement_json_from_rust_values() {
+    let f = json_fn!(|a, b| #[rust_values, expand_key_paths] {
+        query.terms: (Some($a), $b),
+        query.size: Some(10)
+    });
+
+    let j = f("1", "[2]");
+
+    assert_eq!("{\"query\":{\"terms\":[1,[2]],\"size\":10}}", j);
+}
+
+#[test]
+fn can_repeat_replacements_in_rust_value_arrays() {
+    let f = json_fn!(|x, k| #[rust_values] {
+        a: [$x; 2],
+        b: [{ $k: Some($x) }; 2],
+        c: ["${x}"; 3],
+        d: [$x; 0]
+    });
+
+    assert_eq!("{\"a\":[1,1],\"b\":[{\"key\":1},{\"key\":1}],\"c\":[\"1\",\"1\",\"1\"],\"d\":[]}", f("1", "key"));
+}
+
+#[test]
+fn sanitisation_does_not_translate_rust_values_by_default() {
+    let j = "{ a: None, b: [0; 2] }";
+
+    let mut sanitised = String::new();
+    parse_literal(j.as_bytes(), &mut sanitised);
+
+    assert_eq!("{\"a\":\"None\",\"b\":[0;2]}", &sanitised);
+}
+
+#[test]
+fn can_generate_extended_json() {
+    let j = json_str!(#[extended_json] {
+        _id: ObjectId("507f1f77bcf86cd799439011"),
+        created: { $gte: ISODate("2016-10-19T08:30:00.5+10:00"), $lt: ISODate(1476858723456) },
+        count: NumberLong(5),
+        n: NumberInt("7"),
+        d: NumberDecimal("1.5"),
+        ts: Timestamp(1565545664, 1),
+        lo: MinKey(),
+        tags: { $in: ["a", "b"] }
+    });
+
+    let expected = concat!(
+        "{\"_id\":{\"$oid\":\"507f1f77bcf86cd799439011\"},",
+        "\"created\":{\"$gte\":{\"$date\":{\"$numberLong\":\"1476829800500\"}},\"$lt\":{\"$date\":{\"$numberLong\":\"1476858723456\"}}},",
+        "\"count\":{\"$numberLong\":\"5\"},",
+        "\"n\":{\"$numberInt\":\"7\"},",
+        "\"d\":{\"$numberDecimal\":\"1.5\"},",
+        "\"ts\":{\"$timestamp\":{\"t\":1565545664,\"i\":1}},",
+        "\"lo\":{\"$minKey\":1},",
+        "\"tags\":{\"$in\":[\"a\",\"b\"]}}"
+    );
+
+    assert_eq!(expected, j);
+}
+
+#[test]
+fn can_generate_replacement_extended_json() {
+    let f = json_fn!(|id, field, min| #[extended_json] {
+        $or: [
+            { _id: ObjectId($id) },
+            { $field: { $gt: $min } }
+        ]
+    });
+
+    let j = f("\"507f1f77bcf86cd799439011\"", "age", "21");
+
+    assert_eq!("{\"$or\":[{\"_id\":{\"$oid\":\"507f1f77bcf86cd799439011\"}},{\"age\":{\"$gt\":21}}]}", j);
+}
+
+#[test]
+fn extended_json_writes_any_operator_key() {
+    let j = json_str!(#[extended_json] { total: { $add: [1, 2] }, $myOperator: 1 });
+
+    assert_eq!("{\"total\":{\"$add\":[1,2]},\"$myOperator\":1}", j);
+
+    let j: &'static JsonStr = json_lit!(#[extended_json] { total: { $add: [1, 2] } });
+
+    assert_eq!("{\"total\":{\"$add\":[1,2]}}", j);
+
+    let f = json_fn!(|field, price| #[extended_json] { $project: { $field: { $add: [$price, 1] } } });
+
+    assert_eq!("{\"$project\":{\"total\":{\"$add\":[\"$price\",1]}}}", f("total", "\"$price\""));
+
+    //Args named like operators are still replacements
+    let f = json_fn!(|sort, v| #[extended_json] { $sort: $v, $type: 1 });
+
+    assert_eq!("{\"name\":1,\"$type\":1}", f("name", "1"));
+
+    let f = json_fn!(|size, v| #[extended_json] { $match: { $size: $v } });
+
+    assert_eq!("{\"$match\":{\"items\":3}}", f("items", "3"));
+}
+
+#[test]
+fn can_generate_extended_json_with_rust_values() {
+    let j = json_str!(#[rust_values, extended_json] {
+        _id: ObjectId("507f1f77bcf86cd799439011"),
+        n: Some(NumberLong(5)),
+        pair: (1, NumberInt(2)),
+        ids: [MinKey(); 2]
+    });
+
+    assert_eq!("{\"_id\":{\"$oid\":\"507f1f77bcf86cd799439011\"},\"n\":{\"$numberLong\":\"5\"},\"pair\":[1,{\"$numberInt\":\"2\"}],\"ids\":[{\"$minKey\":1},{\"$minKey\":1}]}", j);
+
+    let f = json_fn!(|id| #[rust_values, extended_json] { ids: (ObjectId($id), None) });
+
+    assert_eq!("{\"ids\":[{\"$oid\":\"507f1f77bcf86cd799439011\"},null]}", f("\"507f1f77bcf86cd799439011\""));
+}
+
+#[test]
+fn sanitisation_translates_iso_dates() {
+    let opts = ParseOptions {
+        extended_json: true,
+        ..ParseOptions::default()
+    };
+
+    let cases = [
+        ("ISODate('2000-02-29T12:00Z')", "{\"$date\":{\"$numberLong\":\"951825600000\"}}"),
+        ("ISODate('1969-12-31')", "{\"$date\":{\"$numberLong\":\"-86400000\"}}"),
+        ("ISODate('2016-10-19 06:32:03.456789+0530')", "{\"$date\":{\"$numberLong\":\"1476838923456\"}}"),
+        ("ISODate('2000-02-29T23:59:59Z')", "{\"$date\":{\"$numberLong\":\"951868799000\"}}"),
+        ("[ObjectId('507f1f77bcf86cd799439011'), Other('b'), 1E5]", "[{\"$oid\":\"507f1f77bcf86cd799439011\"},\"Other\"(\"b\"),1E5]"),
+        ("[NumberDecimal('NaN'), Timestamp(1, 2), MinKey()]", "[{\"$numberDecimal\":\"NaN\"},{\"$timestamp\":{\"t\":1,\"i\":2}},{\"$minKey\":1}]")
+    ];
+
+    for &(input, expected) in &cases {
+        let mut json = String::new();
+        parse_literal_with(input.as_bytes(), &mut json, &opts);
+
+        assert_eq!(expected, json, "translating {}", input);
+    }
+
+    let mut json = String::new();
+    parse_literal(b"{ _id: ObjectId('a') }", &mut json);
+
+    assert_eq!("{\"_id\":\"ObjectId\"(\"a\")}", json);
+}
+
+#[test]
+fn sanitisation_panics_on_invalid_extended_json_calls() {
+    let opts = ParseOptions {
+        extended_json: true,
+        ..ParseOptions::default()
+    };
+
+    let cases = [
+        ("ObjectId(5)", "expected a string of 24 hex digits"),
+        ("ObjectId('a')", "expected a string of 24 hex digits"),
+        ("ISODate('not a date')", "expected an ISO 8601 date"),
+        ("ISODate('2020-02-30')", "expected an ISO 8601 date"),
+        ("ISODate('2019-02-29')", "expected an ISO 8601 date"),
+        ("ISODate('2020-04-31')", "expected an ISO 8601 date"),
+        ("ISODate('2020-01-01T24:00Z')", "expected an ISO 8601 date"),
+        ("ISODate(1.5)", "expected an ISO 8601 date string or milliseconds"),
+        ("NumberInt(3000000000)", "expected a 32-bit integer"),
+        ("NumberLong('five')", "expected a 64-bit integer"),
+        ("NumberDecimal(true)", "expected a decimal number"),
+        ("Timestamp(1)", "expected the seconds since the Unix epoch"),
+        ("MaxKey(1)", "expected no arguments"),
+        ("NumberLong(a b)", "its arguments aren't valid json")
+    ];
+
+    for &(input, expected) in &cases {
+        let err = std::panic::catch_unwind(|| {
+            let mut json = String::new();
+            parse_literal_with(input.as_bytes(), &mut json, &opts);
+        }).expect_err(input);
+
+        let msg = err.downcast_ref::<String>().expect(input);
+        assert!(msg.contains(expected), "translating {}: {}", input, msg);
+    }
+}
+
+#[test]
+#[should_panic(expected = "invalid MongoDB shell call 'ISODate(\"2020-02-30\")'")]
+fn json_str_panics_on_invalid_extended_json_calls() {
+    json_str!(#[extended_json] { created: ISODate("2020-02-30") });
+}
+
+#[test]
+fn can_generate_json_with_cfg_attributes() {
+    let j = json_str!({
+        query: { match_all: {} },
+        #[cfg(debug_assertions)]
+        explain: true,
+        #[cfg(not(debug_assertions))]
+        release: true,
+        #[cfg(all(any(unix, windows), not(target_os = "missing")))]
+        platform: [1, #[cfg(target_pointer_width = "8")] 2, #[allow(unused)] 3],
+        #[cfg(target_os = "missing")]
+        missing: { a: [1, "}"] }
+    });
+
+    let expected = if cfg!(debug_assertions) {
+        "{\"query\":{\"match_all\":{}},\"explain\":true,\"platform\":[1,3]}"
+    }
+    else {
+        "{\"query\":{\"match_all\":{}},\"release\":true,\"platform\":[1,3]}"
+    };
+
+    assert_eq!(expected, j);
+}
+
+#[test]
+fn can_generate_replacement_json_with_cfg_attributes() {
+    let f = json_fn!(|a, b| {
+        #[cfg(target_os = "missing")]
+        a: $a,
+        b: $b
+    });
+
+    assert_eq!("{\"b\":2}", f("1", "2"));
+}
+
+#[test]
+#[should_panic(expected = "json_str produced invalid json")]
+fn json_str_panics_on_feature_cfg_attributes() {
+    json_str!({
+        #[cfg(not(feature = "missing"))]
+        a: 1
+    });
+}
+
+#[test]
+fn sanitisation_checks_cfg_attributes_against_options() {
+    let j = "{ #[cfg(feature = \"a\")] a: 1, #[cfg(feature = \"b\")] b: 2, #[cfg(any(x, y))] c: [3] }";
+
+    let mut cfg = Cfg::new();
+    cfg.set_value("feature", "a").set("y");
+
+    let opts = ParseOptions {
+        cfg,
+        ..ParseOptions::strict()
+    };
+
+    let mut sanitised = String::new();
+    parse_literal_with(j.as_bytes(), &mut sanitised, &opts);
+
+    assert_eq!("{\"a\":1,\"c\":[3]}", &sanitised);
+
+    assert_eq!(None, opts.cfg.matches("not(y, y)"));
+    assert_eq!(None, opts.cfg.matches("feature = a"));
+
+    assert_eq!(None, Cfg::current().matches("not(feature = \"a\")"));
+    assert_eq!(Some(true), Cfg::current().matches("not(target_os = \"missing\")"));
+}
+
 #[test]
 fn sanitisation_does_not_quote_special_values() {
     let j = "{ \"a\": \"stuff\", \"b\": true, \"c\": false, \"d\": null, \"e\": 3.14e+11 }";
@@ -120,4 +545,1127 @@ fn sanitisation_works_on_empty_string_values() {
     parse_literal(j.as_bytes(), &mut sanitised);
 
     assert_eq!("{\"a\":\"\",\"b\":1}", &sanitised);
-}
\ No newline at end of file
+}
+
+#[test]
+fn can_generate_json_with_partials() {
+    let range = json_fn!(|from, to| {
+        range: {
+            timestamp: {
+                gte: $from,
+                lt: $to
+            }
+        }
+    });
+
+    let f = json_fn!(|from, to, qry| @range {
+        "a": {
+            "b": @range(from, to),
+            "c": $qry
+        }
+    });
+
+    let j = f("10", "20", "\"*\"");
+
+    assert_eq!("{\"a\":{\"b\":{\"range\":{\"timestamp\":{\"gte\":10,\"lt\":20}}},\"c\":\"*\"}}", j);
+}
+
+#[test]
+fn can_generate_json_with_multiple_partials() {
+    let term = json_fn!(|value| { term: { tag: $value } });
+    let size = json_fn!(|value| { size: $value });
+
+    let f = json_fn!(|qry| #[rust_values] @term, @size {
+        query: @term(qry),
+        page: @size(qry),
+        tags: Some(($qry,))
+    });
+
+    let j = f("\"a\"");
+
+    assert_eq!("{\"query\":{\"term\":{\"tag\":\"a\"}},\"page\":{\"size\":\"a\"},\"tags\":[\"a\"]}", j);
+}
+
+#[test]
+fn partials_can_be_shared_by_json_fns() {
+    let term = json_fn!(|value| { term: { tag: $value } });
+
+    let f = json_fn!(|qry| @term { query: @term(qry) });
+    let g = json_fn!(|qry| @term { filter: @term(qry) });
+
+    assert_eq!("{\"query\":{\"term\":{\"tag\":\"a\"}}}", f("\"a\""));
+    assert_eq!("{\"query\":{\"term\":{\"tag\":\"b\"}}}", f("\"b\""));
+    assert_eq!("{\"filter\":{\"term\":{\"tag\":\"a\"}}}", g("\"a\""));
+    assert_eq!("{\"term\":{\"tag\":\"c\"}}", term("\"c\""));
+}
+
+#[test]
+fn parse_fragments_recognises_partials() {
+    let j = "{ \"a\": @ range(field , from), \"b\": $qry }";
+
+    let mut fragments = Vec::new();
+    parse_fragments(j.as_bytes(), &mut fragments);
+
+    match fragments[1] {
+        JsonFragment::Partial(name, ref args) => {
+            assert_eq!("range", name);
+            assert_eq!(vec!["field", "from"], *args);
+        },
+        ref f => panic!("expected partial, found {:?}", f)
+    }
+}
+
+#[test]
+fn can_generate_replacement_json_with_keys() {
+    let f = json_fn!(|field, order| {
+        sort: [
+            { $field: { order: $order } }
+        ]
+    });
+
+    let j = f("user \"name\"", "\"asc\"");
+
+    assert_eq!("{\"sort\":[{\"user \\\"name\\\"\":{\"order\":\"asc\"}}]}", j);
+}
+
+#[test]
+fn parse_fragments_recognises_key_replacements() {
+    let j = "{ $ field : { \"order\": $order } }";
+
+    let mut fragments = Vec::new();
+    parse_fragments(j.as_bytes(), &mut fragments);
+
+    match (&fragments[1], &fragments[3]) {
+        (&JsonFragment::KeyRepl(key), &JsonFragment::Repl(value)) => {
+            assert_eq!("field", key);
+            assert_eq!("order", value);
+        },
+        f => panic!("expected key and value replacements, found {:?}", f)
+    }
+}
+
+#[test]
+fn parse_fragments_never_splits_quoted_dollars() {
+    let j = "{ \"$gt\": \"$x\", '$in': [\"a $ b $\", \"$$\", \"$${date}\"], \"$match\": { \"$$\": \"$ {x}\" } }";
+
+    let mut fragments = Vec::new();
+    parse_fragments(j.as_bytes(), &mut fragments);
+
+    match &fragments[..] {
+        [JsonFragment::Literal(ref l)] => {
+            assert_eq!("{\"$gt\":\"$x\",\"$in\":[\"a $ b $\",\"$$\",\"${date}\"],\"$match\":{\"$$\":\"$ {x}\"}}", l);
+        },
+        f => panic!("expected a single literal, found {:?}", f)
+    }
+}
+
+#[test]
+fn can_generate_replacement_json_with_escaped_sigils() {
+    let f = json_fn!(|field, min| {
+        $$match: { $field: { $$gt: $min, "$lt": 100 } },
+        $$in: [$$a, "$${b}"]
+    });
+
+    assert_eq!("{\"$match\":{\"age\":{\"$gt\":21,\"$lt\":100}},\"$in\":[\"$a\",\"${b}\"]}", f("age", "21"));
+
+    let j = json_str!({ "$gt": 1, $$lt: 2 });
+
+    assert_eq!("{\"$gt\":1,\"$lt\":2}", j);
+}
+
+#[test]
+fn can_render_fragments_with_another_sigil() {
+    use json_str::fragments::Fragments;
+
+    let opts = ParseOptions {
+        sigil: Some('%'),
+        ..ParseOptions::default()
+    };
+
+    let fragments = Fragments::parse_with("{ $match: { %field: { $gt: %min } }, name: \"logs-%{date}\", price: \"$5\", esc: \"%%{x}\", %%k: 1 }", &opts);
+
+    assert_eq!(vec!["field", "min", "date"], fragments.placeholders().collect::<Vec<_>>());
+
+    let j = fragments.render_with(&[("field", "age"), ("min", "21"), ("date", "2016")]).unwrap();
+
+    assert_eq!("{\"$match\":{\"age\":{\"$gt\":21}},\"name\":\"logs-2016\",\"price\":\"$5\",\"esc\":\"%{x}\",\"%k\":1}", j);
+}
+
+#[test]
+fn escape_produces_valid_json_strings() {
+    let mut escaped = String::new();
+    escape("a \"quoted\" \\ string\n\twith\u{1}controls", &mut escaped);
+
+    assert_eq!("a \\\"quoted\\\" \\\\ string\\n\\twith\\u0001controls", &escaped);
+}
+
+#[test]
+fn can_generate_replacement_json_with_interpolated_strings() {
+    let f = json_fn!(|date, qry| {
+        index: "logs-${date}",
+        query: "title:\"${ qry }\" AND cost:$5",
+        raw: "${}"
+    });
+
+    let j = f("2016.10.19", "some \"value\"");
+
+    assert_eq!("{\"index\":\"logs-2016.10.19\",\"query\":\"title:\\\"some \\\"value\\\"\\\" AND cost:$5\",\"raw\":\"${}\"}", j);
+}
+
+#[test]
+fn sanitisation_does_not_interpolate_strings() {
+    let j = "{ \"a\": \"logs-${date}\" }";
+
+    let mut sanitised = String::new();
+    parse_literal(j.as_bytes(), &mut sanitised);
+
+    assert_eq!("{\"a\":\"logs-${date}\"}", &sanitised);
+}
+
+#[test]
+fn can_render_fragments_with_resolvers() {
+    use std::collections::{BTreeMap, HashMap};
+    use json_str::fragments::*;
+
+    let fragments = Fragments::parse("{ $field: { query: $qry, index: \"logs-${date}\" } }");
+
+    let expected = "{\"title\":{\"query\":\"*\",\"index\":\"logs-2016\"}}";
+
+    let mut hash_map = HashMap::new();
+    hash_map.insert("field", "title");
+    hash_map.insert("qry", "\"*\"");
+    hash_map.insert("date", "2016");
+    assert_eq!(expected, fragments.render_with(&hash_map).unwrap());
+
+    let btree_map: BTreeMap<String, String> = hash_map
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    assert_eq!(expected, fragments.render_with(&btree_map).unwrap());
+
+    let pairs = [("field", "title"), ("qry", "\"*\""), ("date", "2016")];
+    assert_eq!(expected, fragments.render_with(&pairs).unwrap());
+    assert_eq!(expected, fragments.render_with(&pairs[..]).unwrap());
+
+    let f = |name: &str| hash_map.get(name).map(|v| v.to_string());
+    assert_eq!(expected, fragments.render_with(&f).unwrap());
+}
+
+#[test]
+fn render_fragments_fails_on_missing_placeholders() {
+    use json_str::fragments::*;
+
+    let fragments = Fragments::parse("{ a: $qry, b: $fields }");
+
+    let err = fragments.render_with(&[("qry", "1")]).unwrap_err();
+
+    assert_eq!("fields", err.name());
+}
+
+#[test]
+fn fragments_list_unique_placeholders() {
+    use json_str::fragments::*;
+
+    let fragments = Fragments::parse("{ $field: { a: $qry, b: @partial(qry, other), c: \"${date}\" } }");
+
+    let placeholders: Vec<_> = fragments.placeholders().collect();
+
+    assert_eq!(vec!["field", "qry", "other", "date"], placeholders);
+}
+
+#[test]
+fn can_render_templates_from_files() {
+    use std::fs;
+    use json_str::templates::*;
+
+    let dir = std::env::temp_dir().join(format!("json_str_templates_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("queries")).unwrap();
+
+    fs::write(dir.join("queries/match.json"), "{ match: { $field: $value } }").unwrap();
+    fs::write(dir.join("search.json"), "{\n  \"query\": @queries/match(value, field),\n  \"size\": $size,\n  \"index\": \"logs-${date}\"\n}").unwrap();
+    fs::write(dir.join("ignored.txt"), "{}").unwrap();
+
+    let mut templates = TemplateSet::load(&dir).unwrap();
+
+    assert_eq!(vec!["queries/match", "search"], templates.names().collect::<Vec<_>>());
+
+    let mut values = Values::new();
+    values.insert("field", "title");
+    values.insert("value", "some \"value\"");
+    values.insert("size", 10);
+    values.insert("date", "2016.10.19");
+
+    let j = templates.render("search", &values).unwrap();
+
+    assert_eq!("{\"query\":{\"match\":{\"title\":\"some \\\"value\\\"\"}},\"size\":10,\"index\":\"logs-2016.10.19\"}", j);
+
+    assert_eq!(RenderError::UnknownTemplate("other".to_owned()), templates.render("other", &values).unwrap_err());
+
+    // Remove a template and add a new one
+    fs::remove_file(dir.join("queries/match.json")).unwrap();
+    fs::write(dir.join("other.json"), "{ a: $field }").unwrap();
+
+    assert!(templates.reload_if_changed().unwrap());
+    assert!(!templates.reload_if_changed().unwrap());
+    assert_eq!(vec!["other", "search"], templates.names().collect::<Vec<_>>());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn templates_are_validated() {
+    use std::fs;
+    use std::io;
+    use json_str::templates::*;
+
+    let dir = std::env::temp_dir().join(format!("json_str_invalid_templates_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    //Raw control characters are escaped like they are by the macros
+    fs::write(dir.join("search.json"), "{ query: $qry, note: \"a\nb\" }").unwrap();
+
+    let templates = TemplateSet::load(&dir).unwrap();
+
+    assert_eq!("{\"query\":1,\"note\":\"a\\nb\"}", templates.render("search", &[("qry", "1")]).unwrap());
+
+    match templates.render("search", &[("qry", "{")]) {
+        Err(RenderError::InvalidJson(_)) => (),
+        r => panic!("expected invalid json error, found {:?}", r)
+    }
+
+    //Partials only see the placeholders they're called with
+    fs::write(dir.join("outer.json"), "{ a: @search(other) }").unwrap();
+
+    let templates = TemplateSet::load(&dir).unwrap();
+
+    assert_eq!("qry", match templates.render("outer", &[("qry", "1"), ("other", "2")]) {
+        Err(RenderError::MissingPlaceholder(ref err)) => err.name().to_owned(),
+        r => panic!("expected missing placeholder error, found {:?}", r)
+    });
+
+    fs::write(dir.join("search.json"), "{ query: $qry qry }").unwrap();
+
+    let err = TemplateSet::load(&dir).unwrap_err();
+
+    assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    assert!(err.to_string().contains("is invalid json"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn render_templates_fails_on_recursive_partials() {
+    use std::fs;
+    use json_str::templates::*;
+
+    let dir = std::env::temp_dir().join(format!("json_str_recursive_templates_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    fs::write(dir.join("a.json"), "{ b: @b(x) }").unwrap();
+    fs::write(dir.join("b.json"), "{ a: @a(x) }").unwrap();
+
+    let templates = TemplateSet::load(&dir).unwrap();
+
+    let err = templates.render("a", &[("x", "1")]).unwrap_err();
+
+    assert_eq!(RenderError::RecursivePartial("a".to_owned()), err);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// A few hundred KB of pretty-printed docs, which is enough to overflow the stack if it's parsed recursively.
+fn large_json(tag: &str) -> String {
+    let docs: Vec<String> = (0..5000)
+        .map(|i| format!("    {{\n      \"id\": {},\n      \"name\": \"doc {}\",\n      \"tag\": {}\n    }}", i, i, tag))
+        .collect();
+
+    format!("{{\n  \"docs\": [\n{}\n  ]\n}}", docs.join(",\n"))
+}
+
+// Check minified `large_json` with a tag of `"a"`.
+fn assert_large_json(json: &str) {
+    assert!(json.starts_with("{\"docs\":[{\"id\":0,\"name\":\"doc 0\",\"tag\":\"a\"},"));
+    assert!(json.ends_with("{\"id\":4999,\"name\":\"doc 4999\",\"tag\":\"a\"}]}"));
+}
+
+#[test]
+fn can_load_large_templates() {
+    use std::fs;
+    use json_str::templates::*;
+
+    let dir = std::env::temp_dir().join(format!("json_str_large_templates_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    fs::write(dir.join("large.json"), large_json("$tag")).unwrap();
+
+    let templates = TemplateSet::load(&dir).unwrap();
+    let j = templates.render("large", &[("tag", "\"a\"")]).unwrap();
+
+    assert_large_json(&j);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn template_values_are_written_as_json() {
+    use json_str::fragments::*;
+    use json_str::templates::*;
+
+    let fragments = Fragments::parse("{ $key: [$s, $n, $b, $none, $list, $raw], s: \"${s}\" }");
+
+    let mut values = Values::new();
+    values.insert("key", "a \"key\"");
+    values.insert("s", "a\nstring");
+    values.insert("n", 3.5);
+    values.insert("b", true);
+    values.insert("none", None::<i32>);
+    values.insert("list", vec![1, 2]);
+    values.insert("raw", Raw("{\"a\":1}"));
+
+    let j = fragments.render_with(&values).unwrap();
+
+    assert_eq!("{\"a \\\"key\\\"\":[\"a\\nstring\",3.5,true,null,[1,2],{\"a\":1}],\"s\":\"a\\nstring\"}", j);
+}
+
+#[test]
+fn non_finite_template_floats_are_written_as_null() {
+    use json_str::fragments::*;
+    use json_str::templates::*;
+
+    let fragments = Fragments::parse("[$nan, $inf, $neg_inf, $f]");
+
+    let mut values = Values::new();
+    values.insert("nan", f64::NAN);
+    values.insert("inf", f32::INFINITY);
+    values.insert("neg_inf", f64::NEG_INFINITY);
+    values.insert("f", 1.5f32);
+
+    let j = fragments.render_with(&values).unwrap();
+
+    assert_eq!("[null,null,null,1.5]", j);
+}
+
+#[test]
+fn build_generates_template_fns() {
+    use std::fs;
+    use json_str::build::*;
+
+    let dir = std::env::temp_dir().join(format!("json_str_build_templates_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("queries")).unwrap();
+
+    fs::write(dir.join("queries/match.json"), "{ match: { $field: $value } }").unwrap();
+    fs::write(dir.join("search.json"), "{ query: @queries/match(value, field), type: $type, size: {PAGE_SIZE}, note: \"a\nb\" }").unwrap();
+
+    let src = generate_templates(&dir).unwrap();
+
+    assert!(src.contains("pub fn queries_match(field: impl ::json_str::templates::TemplateValue, value: impl ::json_str::templates::TemplateValue) -> ::json_str::JsonString {"));
+    assert!(src.contains("pub fn search(value: impl ::json_str::templates::TemplateValue, field: impl ::json_str::templates::TemplateValue, r#type: impl ::json_str::templates::TemplateValue) -> ::json_str::JsonString {"));
+    assert!(src.contains("result.push_str(&queries_match(&field, &value));"));
+    assert!(src.contains("\\\"note\\\":\\\"a\\\\nb\\\"}"));
+    assert!(src.contains("::json_str::JsonString::from_string(result).unwrap_or_else(|e| panic!(\"template 'search' produced invalid json: {}\", e))"));
+    assert!(src.contains("::json_str::templates::TemplateValue::write_escaped(&field, &mut result);"));
+    assert!(src.contains("::json_str::templates::TemplateValue::write_json(&PAGE_SIZE, &mut result);"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn build_fails_on_bad_partial_calls() {
+    use std::fs;
+    use json_str::build::*;
+
+    let dir = std::env::temp_dir().join(format!("json_str_bad_build_templates_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    fs::write(dir.join("match.json"), "{ match: { $field: $value } }").unwrap();
+    fs::write(dir.join("search.json"), "{ query: @match(field) }").unwrap();
+
+    match generate_templates(&dir) {
+        Err(BuildError::PartialArgs { ref partial, ref expected, ref actual, .. }) if partial == "match" && expected == &["field", "value"] && actual == &["field"] => (),
+        r => panic!("expected partial args error, found {:?}", r)
+    }
+
+    fs::write(dir.join("search.json"), "{ query: @match(field, qry) }").unwrap();
+
+    match generate_templates(&dir) {
+        Err(ref err @ BuildError::PartialArgs { .. }) => {
+            assert_eq!("template 'search' calls partial 'match' with args (field, qry), but it expects (field, value)", err.to_string());
+        },
+        r => panic!("expected partial args error, found {:?}", r)
+    }
+
+    fs::write(dir.join("search.json"), "{ query: @other(qry) }").unwrap();
+
+    match generate_templates(&dir) {
+        Err(BuildError::UnknownPartial { ref partial, .. }) if partial == "other" => (),
+        r => panic!("expected unknown partial error, found {:?}", r)
+    }
+
+    fs::write(dir.join("search.json"), "{ query: @match(field, qry) qry }").unwrap();
+
+    match generate_templates(&dir) {
+        Err(BuildError::InvalidJson { ref template, .. }) if template == "search" => (),
+        r => panic!("expected invalid json error, found {:?}", r)
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn build_fails_on_clashing_names_and_recursive_partials() {
+    use std::fs;
+    use json_str::build::*;
+
+    let dir = std::env::temp_dir().join(format!("json_str_clashing_build_templates_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("a")).unwrap();
+    fs::create_dir_all(dir.join("a_b")).unwrap();
+
+    fs::write(dir.join("a/b_c.json"), "{ a: 1 }").unwrap();
+    fs::write(dir.join("a_b/c.json"), "{ b: 1 }").unwrap();
+
+    match generate_templates(&dir) {
+        Err(BuildError::DuplicateName { ref template, ref other, ref ident }) if template == "a_b/c" && other == "a/b_c" && ident == "a_b_c" => (),
+        r => panic!("expected duplicate name error, found {:?}", r)
+    }
+
+    fs::remove_dir_all(dir.join("a_b")).unwrap();
+    fs::write(dir.join("x.json"), "{ y: @y(v) }").unwrap();
+    fs::write(dir.join("y.json"), "{ z: @z(v) }").unwrap();
+    fs::write(dir.join("z.json"), "{ x: @x(v) }").unwrap();
+
+    match generate_templates(&dir) {
+        Err(BuildError::RecursivePartial { ref template }) if template == "x" => (),
+        r => panic!("expected recursive partial error, found {:?}", r)
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn json_macros_return_validated_json() {
+    fn send(body: &JsonStr) -> &str {
+        body
+    }
+
+    let j = json_str!({ a: [1, 2.5e-3, true, null, "b \\ \"c\""] });
+
+    assert_eq!("{\"a\":[1,2.5e-3,true,null,\"b \\\\ \\\"c\\\"\"]}", send(&j));
+}
+
+#[test]
+fn json_strings_are_validated() {
+    assert!(JsonStr::from_str("{\"a\": [1, -0.5, 1E+2, \"\\ud83d\\ude00\"]}").is_ok());
+    assert!(" \"a\" ".parse::<JsonString>().is_ok());
+
+    for invalid in &["", "{a:1}", "{\"a\":1,}", "[1 2]", "01", "1.", "-", "\"\\x\"", "\"\n\"", "\"\\u+123\"", "nul", "{} {}"] {
+        assert!(JsonStr::from_str(invalid).is_err(), "expected {:?} to be invalid", invalid);
+    }
+
+    // Lone surrogates match RFC 8259's grammar, but aren't valid Unicode, so they're rejected like I-JSON does
+    for lone in &["\"\\ud83d\"", "\"\\ude00\"", "\"\\ud83d\\u0041\""] {
+        assert!(JsonStr::from_str(lone).is_err(), "expected {:?} to be rejected", lone);
+    }
+}
+
+#[test]
+fn deeply_nested_json_is_an_error() {
+    let nested = format!("{}{}", "[".repeat(128), "]".repeat(128));
+    assert!(JsonStr::from_str(&nested).is_ok());
+
+    let nested = format!("{}1{}", "[{\"a\":".repeat(64), "}]".repeat(64));
+    assert!(JsonStr::from_str(&nested).is_ok());
+
+    let nested = format!("{}{}", "[".repeat(129), "]".repeat(129));
+    assert_eq!(128, JsonStr::from_str(&nested).unwrap_err().offset());
+
+    let err = JsonStr::from_str(&"[".repeat(200_000)).unwrap_err();
+    assert_eq!("arrays and objects are nested too deeply at byte 128", err.to_string());
+}
+
+#[test]
+fn canonical_json_formats_numbers_like_ecmascript() {
+    let cases = [
+        ("1e+30", "1e+30"),
+        ("4.50", "4.5"),
+        ("2e-3", "0.002"),
+        ("0.000001", "0.000001"),
+        ("1e-7", "1e-7"),
+        ("1E21", "1e+21"),
+        ("1e20", "100000000000000000000"),
+        ("-0", "0"),
+        ("-1.5e2", "-150"),
+        ("333333333.33333329", "333333333.3333333"),
+        ("9007199254740993", "9007199254740992")
+    ];
+
+    for &(number, expected) in &cases {
+        let json = JsonStr::from_str(number).unwrap().to_canonical().unwrap();
+
+        assert_eq!(expected, &*json, "canonicalising {}", number);
+    }
+
+    let err = JsonStr::from_str("[1e400]").unwrap().to_canonical().unwrap_err();
+
+    assert_eq!("1e400", err.number());
+}
+
+#[test]
+fn canonical_json_sorts_keys_by_utf16() {
+    let json = JsonStr::from_str(r#"{"\u20ac":1,"\r":2,"\ufb33":3,"1":4,"\ud83d\ude02":5,"\u00f6":6,"b":{"z":[],"a":"\u000f"}}"#).unwrap();
+
+    let expected = "{\"\\r\":2,\"1\":4,\"b\":{\"a\":\"\\u000f\",\"z\":[]},\"\u{f6}\":6,\"\u{20ac}\":1,\"\u{1f602}\":5,\"\u{fb33}\":3}";
+
+    assert_eq!(expected, &*json.to_canonical().unwrap());
+}
+
+#[test]
+fn can_generate_canonical_json() {
+    let j = json_str!(#[canonical] { currency: "AUD", amount: 1e3, tags: [ 'a', 2.50 ] });
+
+    assert_eq!("{\"amount\":1000,\"currency\":\"AUD\",\"tags\":[\"a\",2.5]}", j);
+
+    let f = json_fn!(|qry| #[canonical] { query: $qry, from: 0 });
+
+    assert_eq!("{\"from\":0,\"query\":{\"a\":1,\"b\":2}}", f("{ \"b\": 2, \"a\": 1.0 }"));
+
+    let j: &'static JsonStr = json_lit!(#[canonical] { b: 1, a: 2 });
+
+    assert_eq!("{\"a\":2,\"b\":1}", j);
+}
+
+#[test]
+#[should_panic(expected = "number '1e400' is out of range for canonical json")]
+fn json_str_panics_on_non_canonical_numbers() {
+    json_str!(#[canonical] { a: 1e400 });
+}
+
+#[test]
+#[should_panic(expected = "duplicate key 'a'")]
+fn json_str_panics_on_duplicate_keys() {
+    json_str!({ a: 1, b: { a: 1 }, a: 2 });
+}
+
+#[test]
+#[should_panic(expected = "duplicate key '$field' in object at '/query/match'")]
+fn json_fn_panics_on_duplicate_key_replacements() {
+    let f = json_fn!(|field, a, b| { query: { match: { $field: $a, $field: $b } } });
+
+    f("title", "\"a\"", "\"b\"");
+}
+
+#[test]
+fn parse_fragments_finds_duplicate_keys() {
+    let mut fragments = Vec::new();
+    parse_fragments(b"{ a: $a, b: [ { c: 1 }, { c: 1, $k: 2 } ], $k: $v }", &mut fragments);
+
+    assert_eq!(None, duplicate_key(&fragments));
+
+    let mut fragments = Vec::new();
+    parse_fragments(b"{ a: $a, b: [ { c: 1 }, { c: 1, c: 2 } ] }", &mut fragments);
+
+    let duplicate = duplicate_key(&fragments).unwrap();
+
+    assert_eq!("c", duplicate.key());
+    assert_eq!("/b/1", duplicate.path());
+}
+
+#[test]
+fn json_strings_list_duplicate_keys() {
+    let json = JsonStr::from_str(r#"{"a/b":{"x":1,"x":2,"~":[],"x":3},"a":{},"a":null}"#).unwrap();
+
+    let duplicates: Vec<_> = json.duplicate_keys().iter().map(|d| d.to_string()).collect();
+
+    assert_eq!(vec![
+        "duplicate key 'x' in object at '/a~1b'",
+        "duplicate key 'x' in object at '/a~1b'",
+        "duplicate key 'a'"
+    ], duplicates);
+}
+
+#[test]
+fn sanitisation_dedups_keys() {
+    use json_str::value::Keep;
+
+    let first = ParseOptions {
+        dedup_keys: Some(Keep::First),
+        ..ParseOptions::default()
+    };
+
+    let mut json = String::new();
+    parse_literal_with(b"{ a: 1, b: { c: 1, d: 2, c: 3 }, a: [{ e: 1, e: 2 }] }", &mut json, &first);
+
+    assert_eq!("{\"a\":1,\"b\":{\"c\":1,\"d\":2}}", json);
+
+    let last = ParseOptions {
+        dedup_keys: Some(Keep::Last),
+        ..ParseOptions::default()
+    };
+
+    let mut json = String::new();
+    parse_literal_with(b"{ a: 1, b: { c: 1, d: 2, c: 3 }, a: [{ e: 1, e: 2 }] }", &mut json, &last);
+
+    assert_eq!("{\"a\":[{\"e\":2}],\"b\":{\"c\":3,\"d\":2}}", json);
+}
+
+#[test]
+#[should_panic(expected = "json_fn produced invalid json")]
+fn json_fn_panics_on_invalid_json() {
+    let f = json_fn!(|a| { a: $a });
+
+    f("not json");
+}
+
+#[test]
+fn json_lit_returns_static_json() {
+    let j: &'static JsonStr = json_lit!({ a: [1, "b"] });
+
+    assert_eq!("{\"a\":[1,\"b\"]}", j);
+}
+
+#[test]
+fn can_generate_json_lit_with_constants() {
+    const INDEX_NAME: &str = "logs \"2016\"";
+    const PAGE_SIZE: usize = 10;
+
+    let f = || -> &'static JsonStr {
+        json_lit!({INDEX_NAME}, {PAGE_SIZE} {
+            index: {INDEX_NAME},
+            size: {PAGE_SIZE},
+            sizes: [{ PAGE_SIZE }, 20]
+        })
+    };
+
+    assert_eq!("{\"index\":\"logs \\\"2016\\\"\",\"size\":10,\"sizes\":[10,20]}", f());
+    assert!(std::ptr::eq(f(), f()));
+}
+
+#[test]
+fn can_include_json_from_files() {
+    let j: &'static JsonStr = include_json!("tests/queries/match_all.json");
+
+    assert_eq!("{\"query\":{\"match_all\":{}},\"size\":10}", j);
+
+    let j: &'static JsonStr = include_json!(#[canonical] "tests/queries/match_all.json");
+
+    assert_eq!("{\"query\":{\"match_all\":{}},\"size\":10}", j);
+}
+
+#[test]
+fn can_include_json_fn_from_files() {
+    let match_clause = json_fn!(|field, qry| { match: { $field: $qry } });
+    let f = include_json_fn!(|field, qry, date| @match_clause "tests/queries/search.json");
+
+    let j = f("title", "\"some value\"", "2016.10.19");
+
+    assert_eq!("{\"query\":{\"bool\":{\"must\":{\"match\":{\"title\":\"some value\"}}}},\"index\":\"logs-2016.10.19\"}", j);
+}
+
+#[test]
+fn parse_fragments_recognises_constants() {
+    use json_str::fragments::Fragments;
+
+    let mut fragments = Vec::new();
+    parse_fragments(b"{ size: {PAGE_SIZE}, query: { match_all: {} } }", &mut fragments);
+
+    match fragments.as_slice() {
+        [JsonFragment::Literal(_), JsonFragment::Const("PAGE_SIZE"), JsonFragment::Literal(ref l)] => {
+            assert_eq!(",\"query\":{\"match_all\":{}}}", l);
+        },
+        f => panic!("unexpected fragments {:?}", f)
+    }
+
+    let fragments = Fragments::parse("{ size: {PAGE_SIZE}, from: $from }");
+
+    assert_eq!(vec!["from"], fragments.placeholders().collect::<Vec<_>>());
+    assert_eq!("{\"size\":10,\"from\":0}", fragments.render_with(&[("PAGE_SIZE", "10"), ("from", "0")]).unwrap());
+}
+
+#[test]
+fn can_generate_json_lines() {
+    let body = json_lines!(
+        { index: { _index: "logs", _id: 1 } },
+        {
+            msg: "a
+b"
+        },
+    );
+
+    assert_eq!("{\"index\":{\"_index\":\"logs\",\"_id\":1}}\n{\"msg\":\"a\\nb\"}\n", body);
+
+    let body = json_lines!(#[canonical] { b: 1, a: 2 });
+
+    assert_eq!("{\"a\":2,\"b\":1}\n", body);
+}
+
+#[test]
+fn ndjson_writer_writes_minified_lines() {
+    use json_str::ndjson::NdjsonWriter;
+
+    let action = json_fn!(|id| { index: { _id: $id } });
+    let source = JsonStr::from_str("{\n  \"msg\": \"a b\",\r\n  \"tags\": [ 1, 2 ]\n}").unwrap();
+
+    let mut writer = NdjsonWriter::new(Vec::new());
+
+    writer.write_all(&[action("1"), source.to_owned()]).unwrap();
+    writer.write_all(vec![source]).unwrap();
+
+    let body = String::from_utf8(writer.into_inner()).unwrap();
+
+    assert_eq!("{\"index\":{\"_id\":1}}\n{\"msg\":\"a b\",\"tags\":[1,2]}\n{\"msg\":\"a b\",\"tags\":[1,2]}\n", body);
+}
+
+#[test]
+fn ndjson_writer_keeps_strings_as_they_are() {
+    use json_str::ndjson::NdjsonWriter;
+
+    let source = JsonStr::from_str("{ \"a\": \"$${x} { 'b' }\", \"c\": [ \"\\\" d \\\\\", \"it's\" ] }").unwrap();
+
+    let mut writer = NdjsonWriter::new(Vec::new());
+    writer.write_json(source).unwrap();
+
+    let body = String::from_utf8(writer.into_inner()).unwrap();
+
+    assert_eq!("{\"a\":\"$${x} { 'b' }\",\"c\":[\"\\\" d \\\\\",\"it's\"]}\n", body);
+}
+
+#[test]
+fn ndjson_writer_writes_large_values() {
+    use json_str::ndjson::NdjsonWriter;
+
+    let mut writer = NdjsonWriter::new(Vec::new());
+    writer.write_json(JsonStr::from_str(&large_json("\"a\"")).unwrap()).unwrap();
+
+    let body = String::from_utf8(writer.into_inner()).unwrap();
+
+    assert_eq!(1, body.lines().count());
+    assert_large_json(body.trim_end());
+}
+
+#[test]
+fn sanitisation_splits_multiple_values() {
+    let mut values = Vec::new();
+    parse_values(b"{ a: 1 }{ b: [1, 2] }\n1 2\n\x1e'a b' \x1e  true\n#[cfg(not(debug_assertions))] { c: 3 } null", &mut values);
+
+    assert_eq!(vec!["{\"a\":1}", "{\"b\":[1,2]}", "1", "2", "\"a b\"", "true", "{\"c\":3}", "null"], values);
+
+    let mut values = Vec::new();
+    parse_values(b"r#\"a b\"# br\"c d\" [r\"e ]\", 1] r\"f\"", &mut values);
+
+    assert_eq!(vec!["\"a b\"", "\"c d\"", "[\"e ]\",1]", "\"f\""], values);
+
+    let mut json = String::new();
+    assert_eq!(2, parse_value_with(b"1 2", &mut json, &ParseOptions::strict()).unwrap_err().count());
+    assert_eq!("", json);
+
+    parse_value_with(b"#[cfg(not(debug_assertions))] 1 #[cfg(debug_assertions)] 2", &mut json, &ParseOptions::strict()).unwrap();
+    assert_eq!(if cfg!(debug_assertions) { "2" } else { "1" }, json);
+}
+
+#[test]
+fn can_parse_json_sequences() {
+    use json_str::value::Value;
+
+    let values = Value::parse_sequence("\x1e{\"a\":1}\n\x1e[true]\n\"b\"\n").unwrap();
+
+    assert_eq!(vec![
+        Value::Object(vec![("a".to_owned(), Value::Number("1".to_owned()))]),
+        Value::Array(vec![Value::Bool(true)]),
+        Value::String("b".to_owned())
+    ], values);
+
+    assert!(Value::parse_sequence("").unwrap().is_empty());
+    assert!(Value::parse_sequence("{}[]").is_err());
+    assert!(Value::parse_sequence("1 {").is_err());
+}
+
+#[test]
+fn ndjson_writer_writes_json_sequences() {
+    use json_str::ndjson::NdjsonWriter;
+
+    let mut writer = NdjsonWriter::json_seq(Vec::new());
+
+    writer.write_json(&json_str!({ a: 1 })).unwrap();
+    writer.write_json(JsonStr::from_str("[ 1, 2 ]").unwrap()).unwrap();
+
+    assert_eq!(&b"\x1e{\"a\":1}\n\x1e[1,2]\n"[..], &writer.into_inner()[..]);
+}
+
+#[test]
+fn can_generate_cbor_and_msgpack() {
+    let cbor: &'static [u8] = cbor_lit!({ a: [1, "b"], c: null });
+
+    assert_eq!(&[0xa2, 0x61, 0x61, 0x82, 0x01, 0x61, 0x62, 0x61, 0x63, 0xf6][..], cbor);
+
+    let msgpack: &'static [u8] = msgpack_lit!(#[rust_values] { a: (1, true), c: None });
+
+    assert_eq!(&[0x82, 0xa1, 0x61, 0x92, 0x01, 0xc3, 0xa1, 0x63, 0xc0][..], msgpack);
+}
+
+#[test]
+fn cbor_encodes_numbers() {
+    let json = JsonStr::from_str("[0, 23, 24, 1000, 1000000000000, 18446744073709551615, -1, -100, -1000, 1.5, 1.1, 1e400, 100000.5, 6.103515625e-5, 5.960464477539063e-8, -0.0]").unwrap();
+
+    let expected = [
+        0x90,
+        0x00,
+        0x17,
+        0x18, 0x18,
+        0x19, 0x03, 0xe8,
+        0x1b, 0x00, 0x00, 0x00, 0xe8, 0xd4, 0xa5, 0x10, 0x00,
+        0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0x20,
+        0x38, 0x63,
+        0x39, 0x03, 0xe7,
+        0xf9, 0x3e, 0x00,
+        0xfb, 0x3f, 0xf1, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9a,
+        0xf9, 0x7c, 0x00,
+        0xfa, 0x47, 0xc3, 0x50, 0x40,
+        0xf9, 0x04, 0x00,
+        0xf9, 0x00, 0x01,
+        0xf9, 0x80, 0x00
+    ];
+
+    assert_eq!(&expected[..], &json.to_cbor()[..]);
+}
+
+#[test]
+fn msgpack_encodes_numbers_and_strings() {
+    let json = JsonStr::from_str("[127, 200, 65536, -1, -32, -33, -200, 1.5, 1.1, \"abcdefghijklmnopqrstuvwxyz0123456\"]").unwrap();
+
+    let mut expected = vec![
+        0x9a,
+        0x7f,
+        0xcc, 0xc8,
+        0xce, 0x00, 0x01, 0x00, 0x00,
+        0xff,
+        0xe0,
+        0xd0, 0xdf,
+        0xd1, 0xff, 0x38,
+        0xca, 0x3f, 0xc0, 0x00, 0x00,
+        0xcb, 0x3f, 0xf1, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9a,
+        0xd9, 33
+    ];
+    expected.extend_from_slice(b"abcdefghijklmnopqrstuvwxyz0123456");
+
+    assert_eq!(expected, json.to_msgpack());
+}
+
+#[test]
+fn can_generate_yaml() {
+    let yaml = yaml_str!({
+        apiVersion: "v1",
+        kind: "Pod",
+        metadata: { name: "web", labels: {} },
+        spec: {
+            containers: [
+                {
+                    name: "web",
+                    ports: [{ containerPort: 80 }, { containerPort: 443 }],
+                    args: ["--verbose", [1, 2]],
+                    env: []
+                }
+            ]
+        }
+    });
+
+    let expected = "\
+apiVersion: v1
+kind: Pod
+metadata:
+  name: web
+  labels: {}
+spec:
+  containers:
+    - name: web
+      ports:
+        - containerPort: 80
+        - containerPort: 443
+      args:
+        - \"--verbose\"
+        - - 1
+          - 2
+      env: []
+";
+
+    assert_eq!(expected, yaml);
+}
+
+#[test]
+fn yaml_quotes_ambiguous_scalars() {
+    let json = JsonStr::from_str(r#"["yes", "No", "null", "~", "", "1e3", "0x1f", "2016-10-19", ".5", ".inf", "-a", "a: b", "a #b", "a:", " a", "a\nb", "\u0085", "yes please", "a-b", 1e3, 2.5E-2, 10, -1.5, true, null]"#).unwrap();
+
+    let expected = "\
+- \"yes\"
+- \"No\"
+- \"null\"
+- \"~\"
+- \"\"
+- \"1e3\"
+- \"0x1f\"
+- \"2016-10-19\"
+- \".5\"
+- \".inf\"
+- \"-a\"
+- \"a: b\"
+- \"a #b\"
+- \"a:\"
+- \" a\"
+- \"a\\nb\"
+- \"\\u0085\"
+- yes please
+- a-b
+- 1.0e+3
+- 2.5e-2
+- 10
+- -1.5
+- true
+- null
+";
+
+    assert_eq!(expected, json.to_yaml());
+    assert_eq!("\"yes\"\n", JsonStr::from_str("\"yes\"").unwrap().to_yaml());
+    assert_eq!("{}\n", JsonStr::from_str("{}").unwrap().to_yaml());
+}
+
+#[test]
+fn can_generate_json_from_rust_string_escapes() {
+    let j = json_str!({
+        unicode: "\u{1F600} \u{e9}",
+        zero: "a\0b",
+        byte: "\x41\x7f",
+        quotes: "\'single\' \"double\"",
+        json: "\n\r\t\\",
+        continued: "a \
+                    b"
+    });
+
+    assert_eq!("{\"unicode\":\"\\ud83d\\ude00 \\u00e9\",\"zero\":\"a\\u0000b\",\"byte\":\"\\u0041\\u007f\",\"quotes\":\"'single' \\\"double\\\"\",\"json\":\"\\n\\r\\t\\\\\",\"continued\":\"a b\"}", j);
+}
+
+#[test]
+fn can_generate_json_from_rust_raw_and_byte_strings() {
+    let j = json_str!({
+        raw: r"C:\path",
+        hashes: r#"raw "quoted""#,
+        more_hashes: r##"a "# b"##,
+        bytes: b"x\0",
+        raw_bytes: br#"\"#
+    });
+
+    assert_eq!("{\"raw\":\"C:\\\\path\",\"hashes\":\"raw \\\"quoted\\\"\",\"more_hashes\":\"a \\\"# b\",\"bytes\":\"x\\u0000\",\"raw_bytes\":\"\\\\\"}", j);
+}
+
+#[test]
+fn sanitisation_keeps_json_escapes() {
+    let j = r#"{"a":"\u00e9\ud83d\ude00\/\b\f"}"#;
+
+    let mut sanitised = String::new();
+    parse_literal(j.as_bytes(), &mut sanitised);
+
+    assert_eq!(r#"{"a":"\u00e9\ud83d\ude00\/\b\f"}"#, &sanitised);
+}
+
+#[test]
+#[cfg(feature = "elasticsearch")]
+fn elasticsearch_accepts_valid_search_bodies() {
+    let j = json_str!(#[elasticsearch] {
+        query: {
+            bool: {
+                must: { match_all: {} },
+                filter: [
+                    { term: { status: "active" } },
+                    { nested: { path: "tags", query: { terms: { "tags.id": [1, 2] } } } }
+                ],
+                minimum_should_match: 1
+            }
+        },
+        aggs: {
+            per_day: {
+                date_histogram: { field: "timestamp", calendar_interval: "day" },
+                aggs: {
+                    errors: { filter: { term: { level: "error" } } }
+                }
+            }
+        },
+        size: 10
+    });
+
+    assert!(j.starts_with("{\"query\":{\"bool\""));
+}
+
+#[test]
+#[cfg(feature = "elasticsearch")]
+fn elasticsearch_accepts_newer_clauses() {
+    let j = json_str!(#[elasticsearch] {
+        retriever: {
+            standard: { query: { sparse_vector: { field: "tokens", inference_id: "elser", query: "rust" } } }
+        },
+        aggs: {
+            per_month: {
+                date_histogram: { field: "timestamp", calendar_interval: "month" },
+                aggs: {
+                    sales: { rate: { field: "price", unit: "day" } },
+                    prices: { boxplot: { field: "price" } },
+                    names: { string_stats: { field: "name" } }
+                }
+            }
+        }
+    });
+
+    assert!(j.starts_with("{\"retriever\""));
+}
+
+#[test]
+#[should_panic(expected = "the elasticsearch option needs the elasticsearch feature")]
+#[cfg(not(feature = "elasticsearch"))]
+fn json_str_panics_on_elasticsearch_without_the_feature() {
+    json_str!(#[elasticsearch] { query: { match_all: {} } });
+}
+
+#[test]
+fn elasticsearch_option_is_always_present() {
+    let opts = ParseOptions {
+        elasticsearch: false,
+        ..ParseOptions::strict()
+    };
+
+    assert!(opts.validate(JsonStr::from_str("{\"quer\":{}}").unwrap()).is_ok());
+}
+
+#[test]
+#[cfg(feature = "elasticsearch")]
+fn elasticsearch_finds_unknown_clauses() {
+    use json_str::elasticsearch;
+
+    let cases = vec![
+        ("{\"query\":{\"bool\":{\"must\":[{\"match_al\":{}}]}}}", "unknown query 'match_al' at '/query/bool/must/0', did you mean 'match_all'?", "/query/bool/must/0"),
+        ("{\"query\":{\"bool\":{\"shuold\":[]}}}", "unknown bool parameter 'shuold' at '/query/bool', did you mean 'should'?", "/query/bool"),
+        ("{\"quer\":{}}", "unknown search body key 'quer', did you mean 'query'?", ""),
+        ("{\"aggs\":{\"a\":{\"term\":{\"field\":\"f\"}}}}", "unknown aggregation 'term' at '/aggs/a', did you mean 'terms'?", "/aggs/a"),
+        ("{\"aggs\":{\"a\":{\"histogram\":{\"field\":\"f\"}}}}", "'histogram' aggregation is missing 'interval' at '/aggs/a/histogram'", "/aggs/a/histogram"),
+        ("{\"aggs\":{\"a\":{\"filters\":{\"filters\":{\"x\":{\"trem\":{}}}}}}}", "unknown query 'trem' at '/aggs/a/filters/filters/x', did you mean 'term'?", "/aggs/a/filters/filters/x"),
+    ];
+
+    for (json, expected, path) in cases {
+        let err = elasticsearch::validate(JsonStr::from_str(json).unwrap()).unwrap_err();
+
+        assert_eq!(expected, err.to_string());
+        assert_eq!(path, err.path());
+    }
+}
+
+#[test]
+#[cfg(feature = "elasticsearch")]
+#[should_panic(expected = "unknown query 'match_al' at '/query', did you mean 'match_all'?")]
+fn json_fn_panics_on_unknown_queries() {
+    let f = json_fn!(|qry| #[elasticsearch] { query: { match_al: $qry } });
+
+    f("{}");
+}